@@ -0,0 +1,1083 @@
+use crate::atomic_file::AtomicFile;
+use crate::image_processor::RGB;
+use crate::quad_tree::{QuadTree, Region};
+use crate::render::RenderOptions;
+use color_quant::NeuQuant;
+use gif::{DisposalMethod, Encoder as GifEncoder, Frame, Repeat};
+use image::codecs::png::PngEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::{ExtendedColorType, ImageEncoder, RgbaImage};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Delay used by APNG and WebP when no frame delay is given. GIF keeps the
+/// encoder default.
+const DEFAULT_FRAME_DELAY_MS: u32 = 100;
+
+/// GIF stores canvas and frame sizes and offsets in 16 bits.
+const GIF_MAX_DIMENSION: u32 = u16::MAX as u32;
+
+/// WebP stores canvas and frame sizes in 14 bits for lossless bitstreams.
+const WEBP_MAX_DIMENSION: u32 = 16384;
+
+const PNG_SIGNATURE: &[u8; 8] = b"\x89PNG\r\n\x1a\n";
+
+/// Container used for the process animation, chosen by output extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnimationFormat {
+    Gif,
+    Apng,
+    WebP,
+    /// Uncompressed YUV4MPEG2 stream for video encoders.
+    Y4m,
+    /// One PNG per frame, named from a printf-style pattern such as
+    /// `frame-%05d.png`.
+    PngSequence,
+}
+
+impl AnimationFormat {
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        match extension.to_lowercase().as_str() {
+            "gif" => Some(AnimationFormat::Gif),
+            "png" | "apng" => Some(AnimationFormat::Apng),
+            "webp" => Some(AnimationFormat::WebP),
+            "y4m" => Some(AnimationFormat::Y4m),
+            _ => None,
+        }
+    }
+
+    /// Like `from_extension`, but a `.png` path with a frame number
+    /// placeholder selects a PNG sequence.
+    pub fn from_path(path: &str) -> Option<Self> {
+        let path = Path::new(path);
+        let format = AnimationFormat::from_extension(path.extension()?.to_str()?)?;
+        let has_placeholder = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| sequence_placeholder(name).is_some());
+
+        match format {
+            AnimationFormat::Apng if has_placeholder => Some(AnimationFormat::PngSequence),
+            format => Some(format),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AnimationFormat::Gif => "GIF",
+            AnimationFormat::Apng => "APNG",
+            AnimationFormat::WebP => "WebP",
+            AnimationFormat::Y4m => "Y4M",
+            AnimationFormat::PngSequence => "PNG sequence",
+        }
+    }
+}
+
+/// Finds a `%d` or `%0Nd` placeholder, returning its byte range and the
+/// zero padding width.
+fn sequence_placeholder(pattern: &str) -> Option<(std::ops::Range<usize>, usize)> {
+    let start = pattern.find('%')?;
+    let rest = &pattern[start + 1..];
+    let digits = rest.chars().take_while(|c| c.is_ascii_digit()).count();
+    if !rest[digits..].starts_with('d') {
+        return None;
+    }
+    let width = rest[..digits].parse().unwrap_or(0);
+    Some((start..start + digits + 2, width))
+}
+
+/// File name of frame `index` of the PNG sequence `pattern`, counting from 1.
+pub fn sequence_frame(pattern: &str, index: u32) -> Option<String> {
+    let (placeholder, width) = sequence_placeholder(pattern)?;
+    Some(format!(
+        "{}{:0width$}{}",
+        &pattern[..placeholder.start],
+        index,
+        &pattern[placeholder.end..],
+        width = width
+    ))
+}
+
+/// Existing files a PNG sequence written to `pattern` could replace: those
+/// next to its frames and named like one, whatever the frame number.
+pub fn existing_sequence_frames(pattern: &str) -> Vec<String> {
+    let Some((placeholder, _)) = sequence_placeholder(pattern) else {
+        return Vec::new();
+    };
+    let head = &pattern[..placeholder.start];
+    let tail = &pattern[placeholder.end..];
+    let (directory, prefix) =
+        head.split_at(head.rfind(std::path::is_separator).map_or(0, |i| i + 1));
+    let Ok(entries) = std::fs::read_dir(if directory.is_empty() { "." } else { directory }) else {
+        return Vec::new();
+    };
+
+    let mut frames: Vec<String> = entries
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|name| {
+            name.strip_prefix(prefix)
+                .and_then(|rest| rest.strip_suffix(tail))
+                .is_some_and(|number| {
+                    !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit())
+                })
+        })
+        .map(|name| format!("{}{}", directory, name))
+        .collect();
+    frames.sort_unstable();
+    frames
+}
+
+/// Palette index reserved for transparent pixels in GIF frames.
+const TRANSPARENT_INDEX: u8 = u8::MAX;
+
+/// Samples fed to NeuQuant when the colours don't fit in the palette.
+const PALETTE_SAMPLE_BUDGET: u64 = 1 << 20;
+
+/// One palette shared by every GIF frame, stored as the global colour table.
+/// Built from the colours of the finished tree, so frames no longer flicker
+/// from being quantized independently.
+pub struct GlobalPalette {
+    colors: Vec<u8>,
+    lookup: HashMap<[u8; 3], u8>,
+}
+
+impl GlobalPalette {
+    /// The palette for every state of `quad_tree` drawn with `options`. Leaf
+    /// and outline colours come first; the final render adds the background,
+    /// anti-aliased edges and blended outlines.
+    pub fn for_tree(quad_tree: &QuadTree, options: &RenderOptions) -> Self {
+        let extra = options
+            .outline
+            .map(|style| style.palette_colors(quad_tree.max_depth()))
+            .unwrap_or_default();
+        let mut rendered: BTreeMap<[u8; 3], u64> = BTreeMap::new();
+        for pixel in quad_tree.render_rgb(options).pixels() {
+            *rendered.entry(pixel.0).or_default() += 1;
+        }
+        let mut rendered: Vec<(RGB<u8>, u64)> = rendered
+            .into_iter()
+            .map(|([r, g, b], count)| (RGB::new(r, g, b), count))
+            .collect();
+        rendered.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+
+        Self::new(
+            &quad_tree.weighted_colors(options.color_mode),
+            &extra,
+            &rendered,
+        )
+    }
+
+    /// `colors` are the node colours with their weights; `extra` colours
+    /// (like the outline) always get an exact entry. `rendered` colours,
+    /// most frequent first, fill the entries left over, or are quantized
+    /// along with `colors` when those don't fit.
+    pub fn new(colors: &[(RGB<u8>, u64)], extra: &[RGB<u8>], rendered: &[(RGB<u8>, u64)]) -> Self {
+        let mut lookup: HashMap<[u8; 3], u8> = HashMap::new();
+        let mut exact = Vec::new();
+        let mut overflow = false;
+        for color in extra.iter().chain(colors.iter().map(|(color, _)| color)) {
+            let key = [color.r, color.g, color.b];
+            if lookup.contains_key(&key) {
+                continue;
+            }
+            if exact.len() == TRANSPARENT_INDEX as usize {
+                overflow = true;
+                break;
+            }
+            lookup.insert(key, exact.len() as u8);
+            exact.push(key);
+        }
+
+        if !overflow {
+            for (color, _) in rendered {
+                if exact.len() == TRANSPARENT_INDEX as usize {
+                    break;
+                }
+                let key = [color.r, color.g, color.b];
+                if let Entry::Vacant(entry) = lookup.entry(key) {
+                    entry.insert(exact.len() as u8);
+                    exact.push(key);
+                }
+            }
+            return Self::from_exact(exact, lookup);
+        }
+
+        // Too many colours: quantize the area-weighted samples, keeping
+        // exact entries for the extra colours
+        let total_weight: u64 = colors
+            .iter()
+            .chain(rendered)
+            .map(|(_, weight)| weight)
+            .sum();
+        let mut samples = Vec::new();
+        for (color, weight) in colors.iter().chain(rendered) {
+            let repeats = (weight * PALETTE_SAMPLE_BUDGET / total_weight.max(1)).max(1);
+            for _ in 0..repeats {
+                samples.extend_from_slice(&[color.r, color.g, color.b, u8::MAX]);
+            }
+        }
+
+        let quantized_len = TRANSPARENT_INDEX as usize - extra.len();
+        let mut palette = NeuQuant::new(10, quantized_len, &samples).color_map_rgb();
+        let mut lookup = HashMap::new();
+        for (i, color) in extra.iter().enumerate() {
+            lookup.insert([color.r, color.g, color.b], (quantized_len + i) as u8);
+            palette.extend_from_slice(&[color.r, color.g, color.b]);
+        }
+        palette.extend_from_slice(&[0, 0, 0]);
+
+        Self {
+            colors: palette,
+            lookup,
+        }
+    }
+
+    fn from_exact(exact: Vec<[u8; 3]>, lookup: HashMap<[u8; 3], u8>) -> Self {
+        let mut colors: Vec<u8> = exact.into_iter().flatten().collect();
+        colors.resize(TRANSPARENT_INDEX as usize * 3 + 3, 0);
+        Self { colors, lookup }
+    }
+
+    /// Index of the entry closest to `pixel`, which is exact for the
+    /// colours the palette was built from.
+    fn index_of(&mut self, pixel: [u8; 3]) -> u8 {
+        if let Some(&index) = self.lookup.get(&pixel) {
+            return index;
+        }
+        let distance = |entry: &[u8]| -> u32 {
+            entry
+                .iter()
+                .zip(pixel)
+                .map(|(&a, b)| (a as i32 - b as i32).unsigned_abs().pow(2))
+                .sum()
+        };
+        let index = self
+            .colors
+            .chunks_exact(3)
+            .take(TRANSPARENT_INDEX as usize)
+            .enumerate()
+            .min_by_key(|(_, entry)| distance(entry))
+            .map_or(0, |(index, _)| index as u8);
+        self.lookup.insert(pixel, index);
+        index
+    }
+}
+
+/// Frame delays for animated output, in milliseconds. `None` keeps the
+/// encoder default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FrameTiming {
+    pub frame_delay: Option<u32>,
+    pub hold_final: Option<u32>,
+}
+
+/// One container format. Frames after the first cover only part of the
+/// canvas and are composited over the previous frame.
+trait FrameEncoder {
+    /// Whether transparent pixels leave the previous frame visible. When
+    /// false, unchanged pixels are sent as they are instead.
+    fn blends(&self) -> bool {
+        true
+    }
+
+    /// Frame offsets must be a multiple of this.
+    fn offset_alignment(&self) -> u32 {
+        1
+    }
+
+    /// Whether every frame must cover the whole canvas.
+    fn full_frames(&self) -> bool {
+        false
+    }
+
+    fn write_frame(
+        &mut self,
+        left: u32,
+        top: u32,
+        pixels: &RgbaImage,
+        delay_ms: Option<u32>,
+    ) -> Result<(), String>;
+
+    fn finish(self: Box<Self>) -> Result<(), String>;
+}
+
+/// Streams frames to an animation as they are captured. After the first
+/// frame, only the region that changed is encoded, with unchanged pixels left
+/// transparent so the previous frame shows through.
+pub struct AnimationWriter {
+    encoder: Box<dyn FrameEncoder>,
+    canvas: RgbaImage,
+    timing: FrameTiming,
+    /// The newest frame is held back so its delay can be replaced by
+    /// `hold_final` if it turns out to be the last one.
+    pending: Option<(u32, u32, RgbaImage)>,
+    frames_written: usize,
+}
+
+impl AnimationWriter {
+    /// Creates a writer for a single-file format. PNG sequences write one
+    /// file per frame, see `png_sequence`.
+    /// GIF frames use `palette` when given, and are quantized one by one
+    /// otherwise.
+    pub fn new<W: Write + Seek + 'static>(
+        format: AnimationFormat,
+        writer: W,
+        first_frame: RgbaImage,
+        timing: FrameTiming,
+        palette: Option<GlobalPalette>,
+    ) -> Result<Self, String> {
+        let (width, height) = first_frame.dimensions();
+        let frame_delay = timing.frame_delay.unwrap_or(DEFAULT_FRAME_DELAY_MS);
+        let encoder: Box<dyn FrameEncoder> = match format {
+            AnimationFormat::Gif => Box::new(GifFrameEncoder::new(writer, width, height, palette)?),
+            AnimationFormat::Apng => Box::new(ApngFrameEncoder::new(writer, width, height)?),
+            AnimationFormat::WebP => Box::new(WebpFrameEncoder::new(writer, width, height)?),
+            AnimationFormat::Y4m => {
+                Box::new(Y4mFrameEncoder::new(writer, width, height, frame_delay)?)
+            }
+            AnimationFormat::PngSequence => {
+                return Err("PNG sequences are written to one file per frame".to_string());
+            }
+        };
+        Self::with_encoder(encoder, first_frame, timing)
+    }
+
+    /// Creates a writer that saves every frame as a PNG named by `pattern`,
+    /// which must contain a `%d` or `%0Nd` placeholder for the frame number.
+    /// Existing frame files are only replaced if `replace` is set.
+    pub fn png_sequence(
+        pattern: &str,
+        first_frame: RgbaImage,
+        timing: FrameTiming,
+        replace: bool,
+    ) -> Result<Self, String> {
+        let frame_delay = timing.frame_delay.unwrap_or(DEFAULT_FRAME_DELAY_MS);
+        let encoder = Box::new(PngSequenceEncoder::new(pattern, frame_delay, replace)?);
+        Self::with_encoder(encoder, first_frame, timing)
+    }
+
+    fn with_encoder(
+        encoder: Box<dyn FrameEncoder>,
+        first_frame: RgbaImage,
+        timing: FrameTiming,
+    ) -> Result<Self, String> {
+        let mut frame = first_frame.clone();
+        for pixel in frame.pixels_mut() {
+            pixel.0[3] = u8::MAX;
+        }
+
+        let mut writer = Self {
+            encoder,
+            canvas: first_frame,
+            timing,
+            pending: None,
+            frames_written: 0,
+        };
+        writer.queue(0, 0, frame)?;
+        Ok(writer)
+    }
+
+    /// Adds a frame covering `region`, given the freshly rendered pixels of
+    /// that region.
+    pub fn push_region(&mut self, region: Region, pixels: RgbaImage) -> Result<(), String> {
+        let ((top, left), _) = region;
+        let alignment = self.encoder.offset_alignment();
+        let (pad_x, pad_y) = (left as u32 % alignment, top as u32 % alignment);
+        let (left, top) = (left as u32 - pad_x, top as u32 - pad_y);
+        let blends = self.encoder.blends();
+        let mut frame = RgbaImage::new(pixels.width() + pad_x, pixels.height() + pad_y);
+
+        for (x, y, frame_pixel) in frame.enumerate_pixels_mut() {
+            let canvas_pixel = self.canvas.get_pixel_mut(left + x, top + y);
+            let fresh_pixel = match (x.checked_sub(pad_x), y.checked_sub(pad_y)) {
+                (Some(x), Some(y)) => Some(pixels.get_pixel(x, y)),
+                _ => None,
+            };
+
+            let changed = fresh_pixel.is_some_and(|pixel| pixel != canvas_pixel);
+            if let Some(pixel) = fresh_pixel {
+                *canvas_pixel = *pixel;
+            }
+            if changed || !blends {
+                *frame_pixel = *canvas_pixel;
+                frame_pixel.0[3] = u8::MAX;
+            }
+        }
+
+        if self.encoder.full_frames() {
+            let mut frame = self.canvas.clone();
+            for pixel in frame.pixels_mut() {
+                pixel.0[3] = u8::MAX;
+            }
+            return self.queue(0, 0, frame);
+        }
+
+        self.queue(left, top, frame)
+    }
+
+    fn queue(&mut self, left: u32, top: u32, frame: RgbaImage) -> Result<(), String> {
+        if let Some((left, top, previous)) = self.pending.replace((left, top, frame)) {
+            self.write(left, top, &previous, self.timing.frame_delay)?;
+        }
+        Ok(())
+    }
+
+    fn write(
+        &mut self,
+        left: u32,
+        top: u32,
+        frame: &RgbaImage,
+        delay_ms: Option<u32>,
+    ) -> Result<(), String> {
+        self.encoder
+            .write_frame(left, top, frame, delay_ms)
+            .map_err(|e| format!("Error encoding frame {}: {}", self.frames_written, e))?;
+        self.frames_written += 1;
+        Ok(())
+    }
+
+    /// Writes the held back frame and closes the container, returning the
+    /// number of frames written.
+    pub fn finish(mut self) -> Result<usize, String> {
+        if let Some((left, top, last)) = self.pending.take() {
+            let delay = self.timing.hold_final.or(self.timing.frame_delay);
+            self.write(left, top, &last, delay)?;
+        }
+        self.encoder.finish()?;
+        Ok(self.frames_written)
+    }
+}
+
+/// Converts milliseconds to the GIF delay unit of 1/100 s.
+fn gif_delay(ms: u32) -> u16 {
+    ms.div_ceil(10).min(u16::MAX as u32) as u16
+}
+
+struct GifFrameEncoder<W: Write> {
+    encoder: GifEncoder<W>,
+    palette: Option<GlobalPalette>,
+}
+
+impl<W: Write> GifFrameEncoder<W> {
+    fn new(
+        writer: W,
+        width: u32,
+        height: u32,
+        palette: Option<GlobalPalette>,
+    ) -> Result<Self, String> {
+        if width > GIF_MAX_DIMENSION || height > GIF_MAX_DIMENSION {
+            return Err(format!(
+                "GIF animations are limited to {0}x{0} pixels",
+                GIF_MAX_DIMENSION
+            ));
+        }
+
+        let global_colors = palette.as_ref().map_or(&[][..], |p| &p.colors);
+        let mut encoder = GifEncoder::new(writer, width as u16, height as u16, global_colors)
+            .map_err(|e| format!("Error creating GIF encoder: {}", e))?;
+        encoder
+            .set_repeat(Repeat::Infinite)
+            .map_err(|e| format!("Error creating GIF encoder: {}", e))?;
+        Ok(Self { encoder, palette })
+    }
+}
+
+impl<W: Write> FrameEncoder for GifFrameEncoder<W> {
+    fn write_frame(
+        &mut self,
+        left: u32,
+        top: u32,
+        pixels: &RgbaImage,
+        delay_ms: Option<u32>,
+    ) -> Result<(), String> {
+        // Frames lie within the canvas, whose size `new` has checked
+        let (width, height) = pixels.dimensions();
+        let mut frame = match &mut self.palette {
+            Some(palette) => {
+                let indices: Vec<u8> = pixels
+                    .pixels()
+                    .map(|p| match p.0 {
+                        [_, _, _, 0] => TRANSPARENT_INDEX,
+                        [r, g, b, _] => palette.index_of([r, g, b]),
+                    })
+                    .collect();
+                Frame {
+                    width: width as u16,
+                    height: height as u16,
+                    buffer: indices.into(),
+                    transparent: Some(TRANSPARENT_INDEX),
+                    ..Frame::default()
+                }
+            }
+            None => {
+                let mut raw_data = pixels.as_raw().clone();
+                Frame::from_rgba_speed(width as u16, height as u16, &mut raw_data, 10)
+            }
+        };
+        frame.left = left as u16;
+        frame.top = top as u16;
+        frame.dispose = DisposalMethod::Keep;
+        if let Some(delay) = delay_ms {
+            frame.delay = gif_delay(delay);
+        }
+        self.encoder.write_frame(&frame).map_err(|e| e.to_string())
+    }
+
+    fn finish(self: Box<Self>) -> Result<(), String> {
+        self.encoder
+            .into_inner()
+            .and_then(|mut writer| writer.flush())
+            .map_err(|e| e.to_string())
+    }
+}
+
+fn write_png_chunk(writer: &mut impl Write, kind: &[u8; 4], data: &[u8]) -> Result<(), String> {
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    crc.update(data);
+
+    writer
+        .write_all(&(data.len() as u32).to_be_bytes())
+        .and_then(|_| writer.write_all(kind))
+        .and_then(|_| writer.write_all(data))
+        .and_then(|_| writer.write_all(&crc.finalize().to_be_bytes()))
+        .map_err(|e| e.to_string())
+}
+
+/// Encodes `pixels` as a standalone PNG and returns the concatenated
+/// contents of its IDAT chunks.
+fn png_image_data(pixels: &RgbaImage) -> Result<Vec<u8>, String> {
+    let mut png = Vec::new();
+    PngEncoder::new(&mut png)
+        .write_image(
+            pixels.as_raw(),
+            pixels.width(),
+            pixels.height(),
+            ExtendedColorType::Rgba8,
+        )
+        .map_err(|e| e.to_string())?;
+
+    let mut data = Vec::new();
+    let mut offset = PNG_SIGNATURE.len();
+    while offset + 8 <= png.len() {
+        let length = u32::from_be_bytes(png[offset..offset + 4].try_into().unwrap()) as usize;
+        let kind = &png[offset + 4..offset + 8];
+        let body = offset + 8;
+        if kind == b"IDAT" {
+            data.extend_from_slice(&png[body..body + length]);
+        }
+        offset = body + length + 4;
+    }
+
+    Ok(data)
+}
+
+/// Writes an APNG by wrapping PNG-compressed frames in `fcTL`/`fdAT`
+/// chunks. The frame count in `acTL` is patched in on finish, since captures
+/// can stop early when the tree converges.
+struct ApngFrameEncoder<W: Write + Seek> {
+    writer: W,
+    actl_position: u64,
+    sequence: u32,
+    frames: u32,
+}
+
+impl<W: Write + Seek> ApngFrameEncoder<W> {
+    fn new(mut writer: W, width: u32, height: u32) -> Result<Self, String> {
+        let mut header = Vec::with_capacity(13);
+        header.extend_from_slice(&width.to_be_bytes());
+        header.extend_from_slice(&height.to_be_bytes());
+        // 8 bit RGBA, deflate, adaptive filtering, no interlacing
+        header.extend_from_slice(&[8, 6, 0, 0, 0]);
+
+        writer.write_all(PNG_SIGNATURE).map_err(|e| e.to_string())?;
+        write_png_chunk(&mut writer, b"IHDR", &header)?;
+        let actl_position = writer.stream_position().map_err(|e| e.to_string())?;
+        write_png_chunk(&mut writer, b"acTL", &[0; 8])?;
+
+        Ok(Self {
+            writer,
+            actl_position,
+            sequence: 0,
+            frames: 0,
+        })
+    }
+
+    fn next_sequence(&mut self) -> u32 {
+        self.sequence += 1;
+        self.sequence - 1
+    }
+}
+
+impl<W: Write + Seek> FrameEncoder for ApngFrameEncoder<W> {
+    fn write_frame(
+        &mut self,
+        left: u32,
+        top: u32,
+        pixels: &RgbaImage,
+        delay_ms: Option<u32>,
+    ) -> Result<(), String> {
+        let delay = delay_ms
+            .unwrap_or(DEFAULT_FRAME_DELAY_MS)
+            .min(u16::MAX as u32) as u16;
+        let first = self.frames == 0;
+
+        let mut control = Vec::with_capacity(26);
+        control.extend_from_slice(&self.next_sequence().to_be_bytes());
+        control.extend_from_slice(&pixels.width().to_be_bytes());
+        control.extend_from_slice(&pixels.height().to_be_bytes());
+        control.extend_from_slice(&left.to_be_bytes());
+        control.extend_from_slice(&top.to_be_bytes());
+        control.extend_from_slice(&delay.to_be_bytes());
+        control.extend_from_slice(&1000u16.to_be_bytes());
+        // Keep the frame after display; blend partial frames over the canvas
+        control.extend_from_slice(&[0, if first { 0 } else { 1 }]);
+        write_png_chunk(&mut self.writer, b"fcTL", &control)?;
+
+        let data = png_image_data(pixels)?;
+        if first {
+            write_png_chunk(&mut self.writer, b"IDAT", &data)?;
+        } else {
+            let mut frame_data = Vec::with_capacity(data.len() + 4);
+            frame_data.extend_from_slice(&self.next_sequence().to_be_bytes());
+            frame_data.extend_from_slice(&data);
+            write_png_chunk(&mut self.writer, b"fdAT", &frame_data)?;
+        }
+
+        self.frames += 1;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<(), String> {
+        write_png_chunk(&mut self.writer, b"IEND", &[])?;
+
+        let mut animation_control = Vec::with_capacity(8);
+        animation_control.extend_from_slice(&self.frames.to_be_bytes());
+        // Loop forever
+        animation_control.extend_from_slice(&0u32.to_be_bytes());
+
+        let end = self.writer.stream_position().map_err(|e| e.to_string())?;
+        self.writer
+            .seek(SeekFrom::Start(self.actl_position))
+            .map_err(|e| e.to_string())?;
+        write_png_chunk(&mut self.writer, b"acTL", &animation_control)?;
+        self.writer
+            .seek(SeekFrom::Start(end))
+            .and_then(|_| self.writer.flush())
+            .map_err(|e| e.to_string())
+    }
+}
+
+fn write_webp_chunk(writer: &mut impl Write, kind: &[u8; 4], data: &[u8]) -> Result<(), String> {
+    writer
+        .write_all(kind)
+        .and_then(|_| writer.write_all(&(data.len() as u32).to_le_bytes()))
+        .and_then(|_| writer.write_all(data))
+        .and_then(|_| {
+            if data.len() % 2 == 1 {
+                writer.write_all(&[0])
+            } else {
+                Ok(())
+            }
+        })
+        .map_err(|e| e.to_string())
+}
+
+fn push_u24(buffer: &mut Vec<u8>, value: u32) {
+    buffer.extend_from_slice(&value.to_le_bytes()[..3]);
+}
+
+/// Encodes `pixels` as a lossless WebP and returns its `VP8L` chunk,
+/// including the chunk header and padding.
+fn webp_lossless_chunk(pixels: &RgbaImage) -> Result<Vec<u8>, String> {
+    let mut webp = Vec::new();
+    WebPEncoder::new_lossless(&mut webp)
+        .encode(
+            pixels.as_raw(),
+            pixels.width(),
+            pixels.height(),
+            ExtendedColorType::Rgba8,
+        )
+        .map_err(|e| e.to_string())?;
+
+    // Skip the RIFF header and look for the bitstream chunk
+    let mut offset = 12;
+    while offset + 8 <= webp.len() {
+        let kind = &webp[offset..offset + 4];
+        let length = u32::from_le_bytes(webp[offset + 4..offset + 8].try_into().unwrap()) as usize;
+        let end = (offset + 8 + length + length % 2).min(webp.len());
+        if kind == b"VP8L" {
+            return Ok(webp[offset..end].to_vec());
+        }
+        offset = end;
+    }
+
+    Err("WebP encoder produced no VP8L chunk".to_string())
+}
+
+/// Writes an animated WebP with one lossless `ANMF` chunk per frame. The
+/// RIFF size is patched in on finish.
+struct WebpFrameEncoder<W: Write + Seek> {
+    writer: W,
+    frames: u32,
+}
+
+impl<W: Write + Seek> WebpFrameEncoder<W> {
+    fn new(mut writer: W, width: u32, height: u32) -> Result<Self, String> {
+        if width > WEBP_MAX_DIMENSION || height > WEBP_MAX_DIMENSION {
+            return Err(format!(
+                "WebP animations are limited to {0}x{0} pixels",
+                WEBP_MAX_DIMENSION
+            ));
+        }
+
+        writer
+            .write_all(b"RIFF\0\0\0\0WEBP")
+            .map_err(|e| e.to_string())?;
+
+        // Animation and alpha flags, then the canvas size
+        let mut header = vec![0x12, 0, 0, 0];
+        push_u24(&mut header, width - 1);
+        push_u24(&mut header, height - 1);
+        write_webp_chunk(&mut writer, b"VP8X", &header)?;
+
+        // Transparent background, loop forever
+        write_webp_chunk(&mut writer, b"ANIM", &[0, 0, 0, 0, 0, 0])?;
+
+        Ok(Self { writer, frames: 0 })
+    }
+}
+
+impl<W: Write + Seek> FrameEncoder for WebpFrameEncoder<W> {
+    fn blends(&self) -> bool {
+        false
+    }
+
+    /// Frame offsets are stored halved.
+    fn offset_alignment(&self) -> u32 {
+        2
+    }
+
+    fn write_frame(
+        &mut self,
+        left: u32,
+        top: u32,
+        pixels: &RgbaImage,
+        delay_ms: Option<u32>,
+    ) -> Result<(), String> {
+        let mut frame = Vec::new();
+        push_u24(&mut frame, left / 2);
+        push_u24(&mut frame, top / 2);
+        push_u24(&mut frame, pixels.width() - 1);
+        push_u24(&mut frame, pixels.height() - 1);
+        push_u24(
+            &mut frame,
+            delay_ms.unwrap_or(DEFAULT_FRAME_DELAY_MS).min(0xFF_FFFF),
+        );
+        // Do not blend: decoders approximate alpha blending and would shift
+        // colours by one step, so frames carry their unchanged pixels instead
+        frame.push(0b10);
+        frame.extend_from_slice(&webp_lossless_chunk(pixels)?);
+        write_webp_chunk(&mut self.writer, b"ANMF", &frame)?;
+
+        self.frames += 1;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<(), String> {
+        let end = self.writer.stream_position().map_err(|e| e.to_string())?;
+        let riff_size = u32::try_from(end - 8)
+            .map_err(|_| "WebP animation exceeds the 4 GB RIFF limit".to_string())?;
+        self.writer
+            .seek(SeekFrom::Start(4))
+            .and_then(|_| self.writer.write_all(&riff_size.to_le_bytes()))
+            .and_then(|_| self.writer.seek(SeekFrom::Start(end)))
+            .and_then(|_| self.writer.flush())
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Number of times a frame is repeated so a format with a fixed frame rate
+/// shows it for `delay_ms`.
+fn frame_repeats(delay_ms: Option<u32>, frame_delay: u32) -> u32 {
+    delay_ms.map_or(1, |delay| {
+        ((delay + frame_delay / 2) / frame_delay.max(1)).max(1)
+    })
+}
+
+/// Writes an uncompressed 4:4:4 YUV4MPEG2 stream. The frame rate is fixed by
+/// the frame delay, so longer delays (like the final hold) repeat frames.
+struct Y4mFrameEncoder<W: Write> {
+    writer: W,
+    frame_delay: u32,
+    planes: Vec<u8>,
+}
+
+impl<W: Write> Y4mFrameEncoder<W> {
+    fn new(mut writer: W, width: u32, height: u32, frame_delay: u32) -> Result<Self, String> {
+        let frame_delay = frame_delay.max(1);
+        writeln!(
+            writer,
+            "YUV4MPEG2 W{} H{} F1000:{} Ip A1:1 C444",
+            width, height, frame_delay
+        )
+        .map_err(|e| e.to_string())?;
+
+        Ok(Self {
+            writer,
+            frame_delay,
+            planes: Vec::with_capacity((width * height * 3) as usize),
+        })
+    }
+}
+
+impl<W: Write> FrameEncoder for Y4mFrameEncoder<W> {
+    fn full_frames(&self) -> bool {
+        true
+    }
+
+    fn write_frame(
+        &mut self,
+        _left: u32,
+        _top: u32,
+        pixels: &RgbaImage,
+        delay_ms: Option<u32>,
+    ) -> Result<(), String> {
+        // BT.601 limited range, one plane at a time
+        let rgb = || {
+            pixels
+                .pixels()
+                .map(|p| (p.0[0] as i32, p.0[1] as i32, p.0[2] as i32))
+        };
+        self.planes.clear();
+        self.planes
+            .extend(rgb().map(|(r, g, b)| (((66 * r + 129 * g + 25 * b + 128) >> 8) + 16) as u8));
+        self.planes
+            .extend(rgb().map(|(r, g, b)| (((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128) as u8));
+        self.planes
+            .extend(rgb().map(|(r, g, b)| (((112 * r - 94 * g - 18 * b + 128) >> 8) + 128) as u8));
+
+        for _ in 0..frame_repeats(delay_ms, self.frame_delay) {
+            self.writer
+                .write_all(b"FRAME\n")
+                .and_then(|_| self.writer.write_all(&self.planes))
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<(), String> {
+        self.writer.flush().map_err(|e| e.to_string())
+    }
+}
+
+/// Saves each frame as its own PNG. Like Y4M, timing is expressed by
+/// repeating frames at a fixed rate.
+struct PngSequenceEncoder {
+    pattern: String,
+    frame_delay: u32,
+    index: u32,
+    /// Whether existing frame files may be replaced.
+    replace: bool,
+}
+
+impl PngSequenceEncoder {
+    fn new(pattern: &str, frame_delay: u32, replace: bool) -> Result<Self, String> {
+        if sequence_placeholder(pattern).is_none() {
+            return Err(format!(
+                "PNG sequence pattern '{}' needs a frame number placeholder like %05d",
+                pattern
+            ));
+        }
+
+        Ok(Self {
+            pattern: pattern.to_string(),
+            frame_delay,
+            index: 1,
+            replace,
+        })
+    }
+}
+
+impl FrameEncoder for PngSequenceEncoder {
+    fn full_frames(&self) -> bool {
+        true
+    }
+
+    fn write_frame(
+        &mut self,
+        _left: u32,
+        _top: u32,
+        pixels: &RgbaImage,
+        delay_ms: Option<u32>,
+    ) -> Result<(), String> {
+        for _ in 0..frame_repeats(delay_ms, self.frame_delay) {
+            let path = sequence_frame(&self.pattern, self.index)
+                .expect("pattern was checked for a placeholder");
+            let (atomic, file) =
+                AtomicFile::create(&path, self.replace).map_err(|e| format!("{}: {}", path, e))?;
+            let mut writer = BufWriter::new(file);
+            PngEncoder::new(&mut writer)
+                .write_image(
+                    pixels.as_raw(),
+                    pixels.width(),
+                    pixels.height(),
+                    ExtendedColorType::Rgba8,
+                )
+                .map_err(|e| format!("{}: {}", path, e))?;
+            writer
+                .into_inner()
+                .map_err(|e| e.into_error())
+                .and_then(|file| {
+                    drop(file);
+                    atomic.commit()
+                })
+                .map_err(|e| format!("{}: {}", path, e))?;
+            self.index += 1;
+        }
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_processor::ImageData;
+    use crate::quad_tree::SplitOptions;
+    use crate::render::{LeafShape, OutlineStyle};
+    use image::codecs::gif::GifDecoder;
+    use image::{AnimationDecoder, DynamicImage, RgbImage};
+    use std::fs::{self, File};
+    use std::io::BufReader;
+
+    fn split_tree(splits: u32) -> QuadTree {
+        let data: Vec<Vec<RGB<u64>>> = (0..32u64)
+            .map(|y| {
+                (0..32u64)
+                    .map(|x| RGB::new(x * 8, y * 8, (x + y) * 4))
+                    .collect()
+            })
+            .collect();
+        let image_data = ImageData::new(&data, false).unwrap();
+        let mut quad_tree = QuadTree::new(image_data, SplitOptions::default());
+        for _ in 0..splits {
+            quad_tree.split_next();
+        }
+        quad_tree
+    }
+
+    /// Encodes the current state of `quad_tree` as a one frame GIF with its
+    /// global palette and decodes it again.
+    fn gif_round_trip(name: &str, quad_tree: &QuadTree, options: &RenderOptions) -> RgbImage {
+        let path = std::env::temp_dir().join(format!(
+            "img-compressor-{}-{}.gif",
+            name,
+            std::process::id()
+        ));
+        let first_frame =
+            quad_tree.render_rgba_region(options, quad_tree.full_region(), quad_tree.splits());
+        let palette = GlobalPalette::for_tree(quad_tree, options);
+        let writer = BufWriter::new(File::create(&path).unwrap());
+        AnimationWriter::new(
+            AnimationFormat::Gif,
+            writer,
+            first_frame,
+            FrameTiming::default(),
+            Some(palette),
+        )
+        .unwrap()
+        .finish()
+        .unwrap();
+
+        let decoder = GifDecoder::new(BufReader::new(File::open(&path).unwrap())).unwrap();
+        let frame = decoder.into_frames().next().unwrap().unwrap();
+        fs::remove_file(&path).unwrap();
+        DynamicImage::ImageRgba8(frame.into_buffer()).to_rgb8()
+    }
+
+    #[test]
+    fn gif_frame_matches_render() {
+        let quad_tree = split_tree(4);
+        let mut outline = OutlineStyle::new(RGB::new(0, 0, 0));
+        outline.alpha = 0.5;
+        let options = RenderOptions {
+            outline: Some(outline),
+            shape: LeafShape::Circle,
+            background: RGB::new(255, 255, 255),
+            ..RenderOptions::default()
+        };
+        assert_eq!(
+            gif_round_trip("exact", &quad_tree, &options),
+            quad_tree.render_rgb(&options)
+        );
+    }
+
+    #[test]
+    fn quantized_gif_frame_stays_close_to_render() {
+        let quad_tree = split_tree(300);
+        let options = RenderOptions {
+            shape: LeafShape::Circle,
+            background: RGB::new(255, 255, 255),
+            ..RenderOptions::default()
+        };
+        let render = quad_tree.render_rgb(&options);
+        let decoded = gif_round_trip("quantized", &quad_tree, &options);
+        // Far more colours than fit, so each pixel gets the nearest entry
+        for (rendered, decoded) in render.pixels().zip(decoded.pixels()) {
+            for channel in 0..3 {
+                assert!(rendered[channel].abs_diff(decoded[channel]) <= 24);
+            }
+        }
+    }
+
+    #[test]
+    fn gif_canvas_over_16_bits_is_rejected() {
+        assert!(GifFrameEncoder::new(Vec::new(), 70000, 4, None).is_err());
+        assert!(GifFrameEncoder::new(Vec::new(), 4, 65536, None).is_err());
+        assert!(GifFrameEncoder::new(Vec::new(), 65535, 4, None).is_ok());
+    }
+
+    #[test]
+    fn unknown_colors_map_to_the_nearest_entry() {
+        let red = RGB::new(255, 0, 0);
+        let blue = RGB::new(0, 0, 255);
+        let mut palette = GlobalPalette::new(&[(red, 1), (blue, 1)], &[], &[]);
+        let red_index = palette.index_of([255, 0, 0]);
+        let blue_index = palette.index_of([0, 0, 255]);
+        assert_ne!(red_index, blue_index);
+        assert_eq!(palette.index_of([200, 40, 30]), red_index);
+        assert_eq!(palette.index_of([30, 40, 200]), blue_index);
+    }
+
+    #[test]
+    fn existing_files_named_like_any_frame_are_found() {
+        let directory =
+            std::env::temp_dir().join(format!("img-compressor-seq-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        for name in [
+            "f-001.png",
+            "f-1234.png",
+            "f-abc.png",
+            "f-.png",
+            "g-002.png",
+            "f-003.gif",
+        ] {
+            fs::write(directory.join(name), b"").unwrap();
+        }
+        let pattern = format!("{}/f-%03d.png", directory.display());
+
+        let frames = existing_sequence_frames(&pattern);
+        fs::remove_dir_all(&directory).unwrap();
+        let expected: Vec<String> = ["f-001.png", "f-1234.png"]
+            .iter()
+            .map(|name| format!("{}/{}", directory.display(), name))
+            .collect();
+        assert_eq!(frames, expected);
+    }
+}
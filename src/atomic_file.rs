@@ -0,0 +1,152 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A file written under a temporary name in the directory of its
+/// destination and moved into place by `commit`. Until then the
+/// destination is untouched, so a failed or interrupted write never leaves
+/// a truncated file behind. Dropping it without committing removes the
+/// temporary file.
+pub struct AtomicFile {
+    /// A second handle to the temporary file, kept to sync it on commit.
+    file: File,
+    temp_path: PathBuf,
+    path: PathBuf,
+    /// Whether `commit` may replace an existing destination.
+    replace: bool,
+    committed: bool,
+}
+
+impl AtomicFile {
+    /// Creates the temporary file for `path`, returning it for writing.
+    /// Unless `replace` is set, committing fails if `path` exists by then.
+    pub fn create(path: impl AsRef<Path>, replace: bool) -> io::Result<(Self, File)> {
+        let path = path.as_ref().to_path_buf();
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file path"))?;
+
+        let mut temp_name = std::ffi::OsString::from(".");
+        temp_name.push(file_name);
+        temp_name.push(format!(".{}.tmp", std::process::id()));
+        let temp_path = path.with_file_name(temp_name);
+
+        let file = File::create(&temp_path)?;
+        Ok((
+            Self {
+                file: file.try_clone()?,
+                temp_path,
+                path,
+                replace,
+                committed: false,
+            },
+            file,
+        ))
+    }
+
+    /// Moves the finished file to the destination, once its contents have
+    /// reached the disk. The file must have been flushed.
+    pub fn commit(mut self) -> io::Result<()> {
+        self.file.sync_all()?;
+        if self.replace {
+            fs::rename(&self.temp_path, &self.path)?;
+        } else {
+            // Unlike a rename, linking never replaces a file that appeared
+            // since the destination was checked
+            fs::hard_link(&self.temp_path, &self.path).map_err(|e| {
+                if e.kind() == io::ErrorKind::AlreadyExists {
+                    io::Error::new(
+                        e.kind(),
+                        format!(
+                            "Output file '{}' already exists, use --force to overwrite it",
+                            self.path.display()
+                        ),
+                    )
+                } else {
+                    e
+                }
+            })?;
+            fs::remove_file(&self.temp_path)?;
+        }
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+/// Writes `contents` to `path` through an `AtomicFile`.
+pub fn write(path: impl AsRef<Path>, contents: impl AsRef<[u8]>, replace: bool) -> io::Result<()> {
+    let (atomic, file) = AtomicFile::create(path, replace)?;
+    let mut file = file;
+    io::Write::write_all(&mut file, contents.as_ref())?;
+    drop(file);
+    atomic.commit()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn temp_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "img-compressor-{}-{}.txt",
+            name,
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn destination_is_untouched_until_commit() {
+        let path = temp_file("atomic-commit");
+        fs::write(&path, "old").unwrap();
+
+        let (atomic, mut file) = AtomicFile::create(&path, true).unwrap();
+        file.write_all(b"new").unwrap();
+        drop(file);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "old");
+
+        atomic.commit().unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(contents, "new");
+    }
+
+    #[test]
+    fn temp_file_is_removed_on_drop() {
+        let path = temp_file("atomic-drop");
+        let (atomic, mut file) = AtomicFile::create(&path, false).unwrap();
+        file.write_all(b"partial").unwrap();
+        let temp_path = atomic.temp_path.clone();
+        assert!(temp_path.exists());
+
+        drop(atomic);
+        assert!(!temp_path.exists());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn commit_without_replace_keeps_a_file_that_appeared() {
+        let path = temp_file("atomic-no-replace");
+        let (atomic, mut file) = AtomicFile::create(&path, false).unwrap();
+        file.write_all(b"new").unwrap();
+        let temp_path = atomic.temp_path.clone();
+        drop(file);
+        fs::write(&path, "old").unwrap();
+
+        let error = atomic.commit().unwrap_err();
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert!(error.to_string().contains("--force"));
+        assert_eq!(contents, "old");
+        assert!(!temp_path.exists());
+    }
+}
@@ -1,30 +1,504 @@
-use clap::Parser;
+use crate::config::expand_presets;
+use crate::image_processor::RGB;
+use crate::output::{self, ErrorKind, OutputMode};
+use crate::quad_tree::SplitOptions;
+use crate::render::{ColorMode, LeafShape, OutlineStyle, OutputScale, RenderOptions};
+use crate::utils::{hex_to_rgb, parse_output_size};
+use clap::{Parser, Subcommand, ValueEnum};
+
+/// The command line: options of a compression, or a subcommand.
+#[derive(Parser)]
+#[command(name = "img-compressor")]
+#[command(about = "Compress images with iterative refinement")]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+#[command(disable_help_subcommand = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Subcommands>,
+
+    #[command(flatten)]
+    pub compress: Option<Args>,
+
+    #[command(flatten)]
+    pub output: OutputArgs,
+}
+
+#[derive(Subcommand)]
+pub enum Subcommands {
+    /// Compare two images, or two compression settings on one image
+    Compare(CompareArgs),
+}
 
 #[derive(Parser)]
 #[command(name = "img-compressor")]
 #[command(about = "Compress images with iterative refinement")]
 pub struct Args {
-    /// Input image file
+    /// Input image file, or - to read it from stdin
     #[arg(value_name = "FILE")]
     pub input_file: String,
 
-    /// Output file path (optional)
-    #[arg(long, value_name = "FILE")]
+    /// Output file path, or - to write to stdout with --format (optional)
+    #[arg(short, long, value_name = "FILE")]
     pub output_file: Option<String>,
 
-    /// Number of refinement iterations
-    #[arg(long, value_name = "N")]
-    pub iterations: u32,
+    /// Format of still outputs regardless of their extension, required with -o - (optional)
+    #[arg(long, value_name = "FORMAT")]
+    pub format: Option<OutputFormat>,
+
+    /// JPEG quality from 1 to 100 (default: 75)
+    #[arg(long, value_name = "QUALITY", value_parser = clap::value_parser!(u8).range(1..=100))]
+    pub jpeg_quality: Option<u8>,
+
+    /// PNG compression level (default: fast)
+    #[arg(long, value_name = "LEVEL")]
+    pub png_compression: Option<PngCompression>,
+
+    /// Leave out the input's ICC profile, EXIF data and timestamp
+    #[arg(long)]
+    pub strip_metadata: bool,
+
+    /// Number of refinement iterations (total, including any resumed from a checkpoint).
+    /// A comma-separated list (e.g. 100,1000,10000) writes one output per level
+    #[arg(long, value_name = "N", value_delimiter = ',', required = true)]
+    pub iterations: Vec<u32>,
 
     /// Outline color in hex format (e.g. #000000) (optional)
     #[arg(long, value_name = "HEX")]
     pub outline: Option<String>,
 
+    /// Outline thickness in pixels, drawn inside each leaf (requires --outline)
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub outline_width: Option<u32>,
+
+    /// Outline color of the deepest leaves in hex format; outlines fade from --outline at the root (optional)
+    #[arg(long, value_name = "HEX")]
+    pub outline_gradient: Option<String>,
+
+    /// Outline opacity over the leaf color, from 0 to 1 (optional)
+    #[arg(long, value_name = "ALPHA")]
+    pub outline_alpha: Option<f64>,
+
+    /// Only outline leaves at least N pixels on each side (optional)
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub outline_min_size: Option<u32>,
+
+    /// Shape drawn for each leaf (default: rectangle)
+    #[arg(long, value_name = "SHAPE")]
+    pub shape: Option<Shape>,
+
+    /// Background color in hex format shown around non-rectangular leaves (default: #FFFFFF)
+    #[arg(long, value_name = "HEX")]
+    pub background: Option<String>,
+
+    /// Render the output FACTOR times the input size, e.g. 4 for a poster from a small source (optional)
+    #[arg(long, value_name = "FACTOR", conflicts_with = "output_size")]
+    pub scale: Option<f64>,
+
+    /// Render the output at an exact size in pixels, e.g. 4000x3000 (optional)
+    #[arg(long, value_name = "WxH")]
+    pub output_size: Option<String>,
+
+    /// Color leaves by their average color or as a heatmap of depth, variance or split order (default: average)
+    #[arg(long, value_name = "MODE")]
+    pub color_mode: Option<LeafColor>,
+
+    /// Also write the quality metrics of each output to a file next to it, e.g. result.json (optional)
+    #[arg(long, value_name = "FORMAT")]
+    pub report: Option<ReportFormat>,
+
+    /// Record iterations, leaf count, estimated size, MSE and PSNR at logarithmic checkpoints to a CSV file (optional)
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["gif_delta", "gif_schedule"])]
+    pub rd_curve: Option<String>,
+
     /// Save the algorithm process to a GIF, save the image every N iterations (optional)
-    #[arg(long, value_name = "N")]
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
     pub gif_delta: Option<u32>,
+
+    /// When to capture GIF frames: every --gif-delta iterations, or at iterations 1, 2, 4, 8, ... (optional)
+    #[arg(long, value_name = "SCHEDULE")]
+    pub gif_schedule: Option<GifSchedule>,
+
+    /// Delay between GIF frames in milliseconds (optional)
+    #[arg(long, value_name = "MS")]
+    pub frame_delay: Option<u32>,
+
+    /// How long to show the final GIF frame in milliseconds before looping (optional)
+    #[arg(long, value_name = "MS")]
+    pub hold_final: Option<u32>,
+
+    /// Weight of the Sobel edge map in the split priority, refining edges before flat areas (optional)
+    #[arg(long, value_name = "WEIGHT")]
+    pub edge_weight: Option<f64>,
+
+    /// Never split a region into leaves smaller than N pixels on a side (optional)
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub min_leaf_size: Option<u32>,
+
+    /// Always split leaves larger than N pixels on a side first, regardless of variance; at least twice --min-leaf-size (optional)
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_leaf_size: Option<u32>,
+
+    /// Stop before --iterations once the output reaches this PSNR in dB (optional)
+    #[arg(long, value_name = "DB")]
+    pub target_psnr: Option<f64>,
+
+    /// Resume from a checkpoint written by --checkpoint for the same input (optional)
+    #[arg(long, value_name = "CHECKPOINT")]
+    pub resume: Option<String>,
+
+    /// Write the quad tree state to a checkpoint file after compressing (optional)
+    #[arg(long, value_name = "FILE")]
+    pub checkpoint: Option<String>,
+
+    /// Overwrite existing output files
+    #[arg(long)]
+    pub force: bool,
+
+    /// Read default options and presets from a TOML file; options on the command line win (optional)
+    #[arg(long, value_name = "FILE")]
+    pub config: Option<String>,
+
+    /// Apply a named preset from --config or a built-in one: thumbnail, poster, pixel-art or timelapse (optional)
+    #[arg(long, value_name = "NAME")]
+    pub preset: Option<String>,
 }
 
-pub fn parse_args() -> Args {
-    Args::parse()
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Png,
+    Jpeg,
+    Webp,
+    Bmp,
+    Tiff,
+    Qoi,
+    Gif,
+    Svg,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum PngCompression {
+    /// Larger files, written quickly
+    Fast,
+    /// A balance of size and speed
+    Default,
+    /// The smallest files, written slowly
+    Best,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum GifSchedule {
+    /// A frame every --gif-delta iterations
+    Linear,
+    /// Frames at iterations 1, 2, 4, 8, ...
+    Exponential,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Shape {
+    /// Fill the whole leaf
+    Rectangle,
+    /// The largest circle that fits in the leaf
+    Circle,
+    /// The ellipse touching all four sides of the leaf
+    Ellipse,
+    /// A rectangle with rounded corners
+    Rounded,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LeafColor {
+    /// The average color of the leaf's pixels
+    Average,
+    /// Depth of the leaf in the tree
+    Depth,
+    /// Standard deviation of the leaf's pixels
+    Variance,
+    /// Iteration at which the leaf was created
+    SplitOrder,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ReportFormat {
+    /// A JSON object per output
+    Json,
+}
+
+/// How much is printed, shared by every command.
+#[derive(clap::Args)]
+pub struct OutputArgs {
+    /// Print nothing but errors
+    #[arg(long, global = true, conflicts_with_all = ["verbose", "json"])]
+    pub quiet: bool,
+
+    /// Also print details such as image sizes, leaf size limits and timings
+    #[arg(long, global = true, conflicts_with = "json")]
+    pub verbose: bool,
+
+    /// Print newline-delimited JSON events instead of text
+    #[arg(long, global = true)]
+    pub json: bool,
+}
+
+impl OutputArgs {
+    pub fn mode(&self) -> OutputMode {
+        if self.json {
+            OutputMode::Json
+        } else if self.quiet {
+            OutputMode::Quiet
+        } else if self.verbose {
+            OutputMode::Verbose
+        } else {
+            OutputMode::Normal
+        }
+    }
+}
+
+/// Compare two images, or the results of two settings on one image.
+#[derive(Parser)]
+pub struct CompareArgs {
+    /// Two images to compare, or one input image with --settings
+    #[arg(value_name = "FILE", num_args = 1..=2, required = true)]
+    pub files: Vec<String>,
+
+    /// Two sets of compression options to run on the input, e.g. "--iterations 100" "--iterations 1000 --edge-weight 2"
+    #[arg(long, value_names = ["A", "B"], num_args = 2, allow_hyphen_values = true)]
+    pub settings: Option<Vec<String>>,
+
+    /// How to show two settings next to each other (default: side-by-side)
+    #[arg(long, value_name = "LAYOUT", requires = "settings")]
+    pub layout: Option<Layout>,
+
+    /// Where to write the difference heatmap or the comparison image (optional)
+    #[arg(short, long, value_name = "FILE")]
+    pub output_file: Option<String>,
+
+    /// Overwrite an existing output file
+    #[arg(long)]
+    pub force: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Layout {
+    /// The two results next to each other
+    SideBySide,
+    /// The left half of the first result and the right half of the second
+    Split,
+}
+
+pub enum Command {
+    Compress(Box<Args>),
+    Compare(CompareArgs),
+}
+
+impl Args {
+    /// Parses one `--settings` value of `compare` as the options of a
+    /// compression of `input_file`.
+    pub fn from_settings(input_file: &str, settings: &str) -> Result<Self, String> {
+        let args = ["img-compressor", input_file]
+            .into_iter()
+            .chain(settings.split_whitespace())
+            .map(String::from)
+            .collect();
+        let args =
+            expand_presets(args).map_err(|e| format!("invalid settings '{}': {}", settings, e))?;
+        Args::try_parse_from(args)
+            .map_err(|e| format!("invalid settings '{}': {}", settings, error_message(&e)))
+    }
+
+    /// How the tree is drawn, checked for consistency.
+    pub fn render_options(&self) -> Result<RenderOptions, String> {
+        let parse_color = |name: &str, hex: &str| {
+            hex_to_rgb(hex).map_err(|e| format!("Invalid {} '{}': {}", name, hex, e))
+        };
+
+        if self.outline.is_none()
+            && (self.outline_width.is_some()
+                || self.outline_gradient.is_some()
+                || self.outline_alpha.is_some()
+                || self.outline_min_size.is_some())
+        {
+            return Err("outline styling options require --outline".to_string());
+        }
+        if let Some(alpha) = self.outline_alpha
+            && !(0.0..=1.0).contains(&alpha)
+        {
+            return Err("outline alpha must be between 0 and 1".to_string());
+        }
+        let outline = match &self.outline {
+            Some(outline_hex) => {
+                let mut style = OutlineStyle::new(parse_color("outline color", outline_hex)?);
+                style.gradient_end = self
+                    .outline_gradient
+                    .as_ref()
+                    .map(|end_hex| parse_color("outline gradient end", end_hex))
+                    .transpose()?;
+                style.width = self.outline_width.map_or(1, |width| width as usize);
+                style.alpha = self.outline_alpha.unwrap_or(1.0);
+                style.min_leaf_size = self.outline_min_size.map(|size| size as usize);
+                Some(style)
+            }
+            None => None,
+        };
+
+        let shape = match self.shape {
+            None | Some(Shape::Rectangle) => LeafShape::Rectangle,
+            Some(Shape::Circle) => LeafShape::Circle,
+            Some(Shape::Ellipse) => LeafShape::Ellipse,
+            Some(Shape::Rounded) => LeafShape::RoundedRectangle,
+        };
+        if self.background.is_some() && shape == LeafShape::Rectangle {
+            return Err("--background only applies to non-rectangular --shape".to_string());
+        }
+        let background = match &self.background {
+            Some(hex) => parse_color("background color", hex)?,
+            None => RGB::new(255, 255, 255),
+        };
+
+        let scale = match (self.scale, &self.output_size) {
+            (Some(factor), _) if !factor.is_finite() || factor <= 0.0 => {
+                return Err("scale must be a positive number".to_string());
+            }
+            (Some(factor), _) => Some(OutputScale::Factor(factor)),
+            (None, Some(size)) => {
+                let (width, height) = parse_output_size(size)
+                    .map_err(|e| format!("Invalid output size '{}': {}", size, e))?;
+                Some(OutputScale::Size { width, height })
+            }
+            (None, None) => None,
+        };
+
+        let color_mode = match self.color_mode {
+            None | Some(LeafColor::Average) => ColorMode::Average,
+            Some(LeafColor::Depth) => ColorMode::Depth,
+            Some(LeafColor::Variance) => ColorMode::Variance,
+            Some(LeafColor::SplitOrder) => ColorMode::SplitOrder,
+        };
+
+        Ok(RenderOptions {
+            outline,
+            shape,
+            background,
+            scale,
+            color_mode,
+        })
+    }
+
+    /// Which regions get split, checked for consistency.
+    pub fn split_options(&self) -> Result<SplitOptions, String> {
+        if let Some(weight) = self.edge_weight
+            && (!weight.is_finite() || weight < 0.0)
+        {
+            return Err("edge weight must be a non-negative number".to_string());
+        }
+        // A leaf over the maximum has to be splittable without going under
+        // the minimum
+        if let (Some(min_size), Some(max_size)) = (self.min_leaf_size, self.max_leaf_size)
+            && max_size / 2 < min_size
+        {
+            return Err(format!(
+                "max leaf size ({}) must be at least twice the min leaf size ({})",
+                max_size, min_size
+            ));
+        }
+        if self.target_psnr.is_some_and(f64::is_nan) {
+            return Err("target PSNR must be a number".to_string());
+        }
+
+        Ok(SplitOptions {
+            edge_weight: self.edge_weight,
+            min_leaf_size: self.min_leaf_size.map(|size| size as usize),
+            max_leaf_size: self.max_leaf_size.map(|size| size as usize),
+            target_psnr: self.target_psnr,
+        })
+    }
+}
+
+/// A clap error on one line, without the usage and help hints that follow it.
+fn error_message(error: &clap::Error) -> String {
+    let message = error.to_string();
+    message
+        .lines()
+        .take_while(|line| !line.is_empty())
+        .map(str::trim)
+        .collect::<Vec<_>>()
+        .join(" ")
+        .trim_start_matches("error: ")
+        .to_string()
+}
+
+/// A compression unless a subcommand is given. A file named like a
+/// subcommand can still be compressed by putting an option first. Sets the
+/// output mode for either.
+pub fn parse_args() -> Command {
+    let args: Vec<String> = std::env::args().collect();
+    // Scripts asking for JSON get usage errors as an event too
+    if args.iter().any(|arg| arg == "--json") {
+        output::set_mode(OutputMode::Json);
+    }
+    let args = expand_presets(args)
+        .unwrap_or_else(|e| output::fail(ErrorKind::Usage, format!("Error: {}", e)));
+    let cli = match Cli::try_parse_from(args) {
+        Ok(cli) => cli,
+        Err(e) if e.use_stderr() && output::mode() == OutputMode::Json => {
+            output::fail(ErrorKind::Usage, error_message(&e))
+        }
+        Err(e) => e.exit(),
+    };
+    output::set_mode(cli.output.mode());
+    match cli {
+        Cli {
+            command: Some(Subcommands::Compare(args)),
+            ..
+        } => Command::Compare(args),
+        Cli { compress, .. } => Command::Compress(Box::new(
+            compress.expect("clap requires an input file without a subcommand"),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_without_a_subcommand_are_a_compression() {
+        let cli = Cli::try_parse_from(["img-compressor", "in.png", "--iterations", "5", "--quiet"])
+            .unwrap();
+
+        assert!(cli.command.is_none());
+        assert_eq!(cli.output.mode(), OutputMode::Quiet);
+        let args = cli.compress.expect("compression options");
+        assert_eq!(args.input_file, "in.png");
+    }
+
+    #[test]
+    fn output_flags_apply_to_compare_too() {
+        let cli = Cli::try_parse_from(["img-compressor", "compare", "a.png", "b.png", "--verbose"])
+            .unwrap();
+
+        assert!(matches!(cli.command, Some(Subcommands::Compare(_))));
+        assert_eq!(cli.output.mode(), OutputMode::Verbose);
+    }
+
+    #[test]
+    fn max_leaf_size_must_leave_room_for_two_min_size_leaves() {
+        let split_options = |min: &str, max: &str| {
+            let cli = Cli::try_parse_from([
+                "img-compressor",
+                "in.png",
+                "--iterations",
+                "5",
+                "--min-leaf-size",
+                min,
+                "--max-leaf-size",
+                max,
+            ])
+            .unwrap();
+            cli.compress.unwrap().split_options()
+        };
+
+        assert!(split_options("8", "10").is_err());
+        assert!(split_options("8", "15").is_err());
+        assert!(split_options("8", "16").is_ok());
+    }
 }
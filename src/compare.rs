@@ -0,0 +1,330 @@
+use crate::atomic_file::AtomicFile;
+use crate::cli::{Args, CompareArgs, Layout};
+use crate::image_processor::ImageData;
+use crate::metadata;
+use crate::metrics::{Metrics, channel_mse, print_similarity, similarity_event, ssim};
+use crate::output::{ErrorKind, Event, detail, fail, status};
+use crate::quad_tree::QuadTree;
+use crate::render::{ColorMode, RenderOptions, color_map, with_legend};
+use crate::utils::{check_overwrite, print_failure, print_step, print_success, split_until};
+use image::{GenericImage, ImageFormat, Rgb, RgbImage};
+use std::io::BufWriter;
+use std::path::Path;
+
+/// Blank columns between the two results of a side-by-side comparison.
+const SIDE_BY_SIDE_GAP: u32 = 8;
+
+/// Width of the line between the two halves of a split view.
+const SPLIT_DIVIDER_WIDTH: u32 = 2;
+
+/// Runs `img-compressor compare`.
+pub fn run(args: &CompareArgs) {
+    match (&args.settings, args.files.as_slice()) {
+        (None, [first, second]) => {
+            compare_images(first, second, args.output_file.as_deref(), args.force)
+        }
+        (None, _) => fail(ErrorKind::Usage, "Error: comparing images takes two files"),
+        (Some(settings), [input_file]) => compare_settings(
+            input_file,
+            (&settings[0], &settings[1]),
+            args.layout.unwrap_or(Layout::SideBySide),
+            args.output_file.as_deref(),
+            args.force,
+        ),
+        (Some(_), _) => fail(
+            ErrorKind::Usage,
+            "Error: --settings takes a single input file",
+        ),
+    }
+}
+
+/// `path`, or `<stem>-<suffix>.png` next to `input_file`, checked for
+/// overwriting any of `inputs`.
+fn output_path(
+    path: Option<&str>,
+    input_file: &str,
+    suffix: &str,
+    inputs: &[&str],
+    force: bool,
+) -> String {
+    let output_file = match path {
+        Some(path) => path.to_string(),
+        None => sibling_file(input_file, suffix)
+            .unwrap_or_else(|e| fail(ErrorKind::Usage, format!("Error: {}", e))),
+    };
+    for input in inputs {
+        if let Err(e) = check_overwrite(&output_file, input, force) {
+            fail(ErrorKind::Output, format!("Error: {}", e));
+        }
+    }
+    output_file
+}
+
+/// Prints how similar two images are and writes a heatmap of where they
+/// differ.
+fn compare_images(first: &str, second: &str, output_file: Option<&str>, force: bool) {
+    status!("Image Compressor - compare");
+    status!("First image: {}", first);
+    status!("Second image: {}", second);
+
+    let output_file = output_path(output_file, first, "diff", &[first, second], force);
+
+    let a = open_image(first);
+    let b = open_image(second);
+    if a.dimensions() != b.dimensions() {
+        fail(
+            ErrorKind::Input,
+            format!(
+                "Error: images differ in size: {}x{} vs {}x{}",
+                a.width(),
+                a.height(),
+                b.width(),
+                b.height()
+            ),
+        );
+    }
+    detail!("Image size: {}x{}", a.width(), a.height());
+
+    let (mse, ssim) = (channel_mse(&a, &b), ssim(&a, &b));
+    status!("Similarity:");
+    print_similarity(mse, ssim);
+    similarity_event(mse, ssim)
+        .string("first", first)
+        .string("second", second)
+        .emit();
+
+    let (heatmap, max_difference) = difference_heatmap(&a, &b);
+    save(&with_legend(&heatmap), &output_file, force);
+    status!(
+        "Legend: RMS difference from 0 (left) to {} (right)",
+        (max_difference * 10.0).round() / 10.0
+    );
+    status!("Difference heatmap saved to: {}", output_file);
+}
+
+/// Compresses one input with two sets of options and shows the results
+/// next to each other.
+fn compare_settings(
+    input_file: &str,
+    settings: (&str, &str),
+    layout: Layout,
+    output_file: Option<&str>,
+    force: bool,
+) {
+    status!("Image Compressor - compare");
+    status!("Input file: {}", input_file);
+
+    let output_file = output_path(output_file, input_file, "compare", &[input_file], force);
+
+    let original = open_image(input_file);
+    let original_bytes = std::fs::metadata(input_file).map_or(0, |metadata| metadata.len());
+    detail!(
+        "Image size: {}x{}, {} bytes",
+        original.width(),
+        original.height(),
+        original_bytes
+    );
+
+    let mut results = Vec::with_capacity(2);
+    for (label, settings) in [("A", settings.0), ("B", settings.1)] {
+        status!();
+        status!("Settings {}: {}", label, settings);
+        let (quad_tree, render_options) = Args::from_settings(input_file, settings)
+            .map_err(Into::into)
+            .and_then(|args| compress(&args, &original))
+            .unwrap_or_else(|e| fail(ErrorKind::Usage, format!("Error: {}", e)));
+
+        let metrics = Metrics::measure(&quad_tree, &original, original_bytes);
+        metrics.print();
+        metrics
+            .event(&output_file)
+            .string("label", label)
+            .string("settings", settings)
+            .emit();
+        let image = quad_tree.render_rgb(&render_options);
+        results.push(if render_options.color_mode == ColorMode::Average {
+            image
+        } else {
+            with_legend(&image)
+        });
+    }
+    status!();
+
+    let combined = match layout {
+        Layout::SideBySide => side_by_side(&results[0], &results[1]),
+        Layout::Split => split_view(&results[0], &results[1])
+            .unwrap_or_else(|e| fail(ErrorKind::Usage, format!("Error: {}", e))),
+    };
+    save(&combined, &output_file, force);
+    status!("Comparison saved to: {}", output_file);
+}
+
+/// Builds and splits the tree described by one `--settings` value.
+fn compress(
+    args: &Args,
+    original: &RgbImage,
+) -> Result<(QuadTree, RenderOptions), Box<dyn std::error::Error>> {
+    if args.iterations.len() > 1 {
+        return Err("--settings takes a single iteration count".into());
+    }
+    if args.output_file.is_some()
+        || args.gif_delta.is_some()
+        || args.gif_schedule.is_some()
+        || args.frame_delay.is_some()
+        || args.hold_final.is_some()
+        || args.rd_curve.is_some()
+        || args.report.is_some()
+        || args.resume.is_some()
+        || args.checkpoint.is_some()
+        || args.format.is_some()
+        || args.jpeg_quality.is_some()
+        || args.png_compression.is_some()
+        || args.strip_metadata
+        || args.force
+    {
+        return Err(
+            "--settings only takes compression and rendering options, not output options".into(),
+        );
+    }
+    let render_options = args.render_options()?;
+    let split_options = args.split_options()?;
+    let iterations = args.iterations[0];
+
+    print_step("Initializing quad tree");
+    let data = ImageData::from_image(original, args.edge_weight.is_some())?;
+    let mut quad_tree = QuadTree::new(data, split_options);
+    print_success();
+
+    status!("Processing {} iterations...", iterations);
+    split_until(&mut quad_tree, iterations, |_| Ok(()))?;
+    status!();
+
+    Ok((quad_tree, render_options))
+}
+
+fn open_image(path: &str) -> RgbImage {
+    print_step(&format!("Loading {}", path));
+    match metadata::open(path) {
+        Ok((image, _)) => {
+            print_success();
+            image.to_rgb8()
+        }
+        Err(_) => {
+            print_failure();
+            fail(
+                ErrorKind::Input,
+                format!("Error: Failed to open image file: {}", path),
+            )
+        }
+    }
+}
+
+fn save(image: &RgbImage, output_file: &str, force: bool) {
+    print_step("Saving image");
+    // The temporary file's name hides the extension, so the format is
+    // worked out from the real one
+    let result = ImageFormat::from_path(output_file).and_then(|format| {
+        let (atomic, file) = AtomicFile::create(output_file, force)?;
+        let mut writer = BufWriter::new(file);
+        image.write_to(&mut writer, format)?;
+        writer.into_inner().map_err(|e| e.into_error())?;
+        atomic.commit()?;
+        Ok(())
+    });
+    match result {
+        Ok(_) => {
+            print_success();
+            Event::new("output_written")
+                .string("output_file", output_file)
+                .emit();
+        }
+        Err(err) => {
+            print_failure();
+            fail(ErrorKind::Output, format!("Error saving image: {}", err));
+        }
+    }
+}
+
+/// `<stem>-<suffix>.png` next to `input_file`.
+fn sibling_file(input_file: &str, suffix: &str) -> Result<String, String> {
+    let input_path = Path::new(input_file);
+    let stem = input_path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .ok_or_else(|| format!("Input file '{}' has no valid filename", input_file))?;
+    Ok(input_path
+        .with_file_name(format!("{}-{}.png", stem, suffix))
+        .to_string_lossy()
+        .into_owned())
+}
+
+/// Colours each pixel by the RMS of its channel differences, relative to
+/// the largest difference. Returns the heatmap and that largest difference.
+fn difference_heatmap(a: &RgbImage, b: &RgbImage) -> (RgbImage, f64) {
+    let differences: Vec<f64> = a
+        .pixels()
+        .zip(b.pixels())
+        .map(|(pa, pb)| {
+            let squared: f64 = (0..3)
+                .map(|channel| (pa[channel] as f64 - pb[channel] as f64).powi(2))
+                .sum();
+            (squared / 3.0).sqrt()
+        })
+        .collect();
+    let max_difference = differences.iter().copied().fold(0.0, f64::max);
+
+    let mut heatmap = RgbImage::new(a.width(), a.height());
+    for (pixel, difference) in heatmap.pixels_mut().zip(differences) {
+        let t = if max_difference > 0.0 {
+            difference / max_difference
+        } else {
+            0.0
+        };
+        let color = color_map(t);
+        *pixel = Rgb([color.r, color.g, color.b]);
+    }
+    (heatmap, max_difference)
+}
+
+/// `left` and `right` next to each other on a white background.
+fn side_by_side(left: &RgbImage, right: &RgbImage) -> RgbImage {
+    let width = left.width() + SIDE_BY_SIDE_GAP + right.width();
+    let height = left.height().max(right.height());
+    let mut output = RgbImage::from_pixel(width, height, Rgb([255, 255, 255]));
+    output
+        .copy_from(left, 0, 0)
+        .expect("left image fits in the comparison");
+    output
+        .copy_from(right, left.width() + SIDE_BY_SIDE_GAP, 0)
+        .expect("right image fits in the comparison");
+    output
+}
+
+/// The left half of `left` and the right half of `right`, with a line
+/// between them.
+fn split_view(left: &RgbImage, right: &RgbImage) -> Result<RgbImage, String> {
+    if left.dimensions() != right.dimensions() {
+        return Err(format!(
+            "split view needs results of the same size, got {}x{} and {}x{}",
+            left.width(),
+            left.height(),
+            right.width(),
+            right.height()
+        ));
+    }
+    let (width, height) = left.dimensions();
+    let middle = width / 2;
+    let divider = middle.saturating_sub(SPLIT_DIVIDER_WIDTH / 2)
+        ..(middle + SPLIT_DIVIDER_WIDTH.div_ceil(2)).min(width);
+
+    let mut output = left.clone();
+    for y in 0..height {
+        for x in middle..width {
+            output.put_pixel(x, y, *right.get_pixel(x, y));
+        }
+        for x in divider.clone() {
+            output.put_pixel(x, y, Rgb([255, 255, 255]));
+        }
+    }
+    Ok(output)
+}
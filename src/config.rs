@@ -0,0 +1,293 @@
+use crate::cli::Cli;
+use clap::CommandFactory;
+use clap::parser::ValueSource;
+use std::fs;
+use toml::{Table, Value};
+
+/// Presets available without a config file. A preset of the same name in
+/// the config file replaces the built-in one.
+const BUILTIN_PRESETS: &str = r##"
+# A small, quick preview
+[preset.thumbnail]
+iterations = 500
+scale = 0.25
+
+# A large print with the tree drawn in
+[preset.poster]
+iterations = 5000
+scale = 4
+outline = "#000000"
+outline-min-size = 8
+
+# Uniform blocks, nothing smaller than 8px or larger than 64px
+[preset.pixel-art]
+iterations = 2000
+min-leaf-size = 8
+max-leaf-size = 64
+
+# An animation that speeds up as the detail gets finer
+[preset.timelapse]
+iterations = 3000
+gif-schedule = "exponential"
+frame-delay = 80
+hold-final = 3000
+"##;
+
+/// The value as a command line argument. Arrays become comma-separated
+/// lists, which is how options such as `--iterations` take several.
+fn to_arg(value: &Value) -> Result<String, String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Integer(i) => Ok(i.to_string()),
+        Value::Float(f) => Ok(f.to_string()),
+        Value::Boolean(_) => Err("expected a value, not true or false".to_string()),
+        Value::Array(items) => items
+            .iter()
+            .map(|item| match item {
+                Value::Array(_) => Err("nested arrays are not supported".to_string()),
+                item => to_arg(item),
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(|items| items.join(",")),
+        Value::Datetime(_) | Value::Table(_) => {
+            Err("expected a string, number or array".to_string())
+        }
+    }
+}
+
+/// `key = value` pairs, keys written like the long option they set.
+type Settings = Vec<(String, Value)>;
+
+/// The contents of a config file: settings for every run, and named
+/// presets applied on top with `--preset`.
+#[derive(Debug, Default)]
+struct Config {
+    defaults: Settings,
+    presets: Vec<(String, Settings)>,
+}
+
+impl Config {
+    fn preset(&self, name: &str) -> Option<&Settings> {
+        self.presets
+            .iter()
+            .find(|(preset, _)| preset == name)
+            .map(|(_, settings)| settings)
+    }
+}
+
+/// Parses a config file: top-level keys are settings for every run, and
+/// the tables under `preset` are the presets.
+fn parse(text: &str, source: &str) -> Result<Config, String> {
+    let table: Table = text
+        .parse()
+        .map_err(|e: toml::de::Error| format!("{}: {}", source, e.to_string().trim_end()))?;
+    let mut config = Config::default();
+
+    for (key, value) in table {
+        if key != "preset" {
+            config.defaults.push((key.replace('_', "-"), value));
+            continue;
+        }
+        let Value::Table(presets) = value else {
+            return Err(format!("{}: 'preset' must be a table of presets", source));
+        };
+        for (name, preset) in presets {
+            let Value::Table(preset) = preset else {
+                return Err(format!("{}: preset '{}' must be a table", source, name));
+            };
+            let settings = preset
+                .into_iter()
+                .map(|(key, value)| (key.replace('_', "-"), value))
+                .collect();
+            config.presets.push((name, settings));
+        }
+    }
+
+    Ok(config)
+}
+
+/// Names of the presets built in and in `config`, for error messages.
+fn preset_names(config: &Config, builtin: &Config) -> String {
+    let mut names: Vec<&str> = builtin
+        .presets
+        .iter()
+        .chain(&config.presets)
+        .map(|(name, _)| name.as_str())
+        .collect();
+    names.sort_unstable();
+    names.dedup();
+    names.join(", ")
+}
+
+/// Inserts the settings of `--config` and `--preset` into `args` as
+/// options, ahead of those given on the command line. Options given on the
+/// command line, and options conflicting with them, are left out, so the
+/// command line always wins; likewise a preset wins over the file's
+/// top-level settings.
+pub fn expand_presets(args: Vec<String>) -> Result<Vec<String>, String> {
+    let command = Cli::command().ignore_errors(true);
+    // Help and version requests, and subcommands, are left to the real parse
+    let Ok(matches) = command.clone().try_get_matches_from(&args) else {
+        return Ok(args);
+    };
+    if matches.subcommand().is_some() {
+        return Ok(args);
+    }
+    let config_file = matches.get_one::<String>("config");
+    let preset = matches.get_one::<String>("preset");
+    if config_file.is_none() && preset.is_none() {
+        return Ok(args);
+    }
+
+    let builtin = parse(BUILTIN_PRESETS, "built-in presets")?;
+    let config = match config_file {
+        Some(path) => {
+            let text = fs::read_to_string(path)
+                .map_err(|e| format!("Unable to read config file '{}': {}", path, e))?;
+            parse(&text, path)?
+        }
+        None => Config::default(),
+    };
+
+    let mut settings = config.defaults.clone();
+    if let Some(name) = preset {
+        let preset = config
+            .preset(name)
+            .or_else(|| builtin.preset(name))
+            .ok_or_else(|| {
+                format!(
+                    "unknown preset '{}', available: {}",
+                    name,
+                    preset_names(&config, &builtin)
+                )
+            })?;
+        settings = merge(&command, settings, preset)?;
+    }
+
+    let given: Vec<&clap::Arg> = command
+        .get_arguments()
+        .filter(|arg| matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine))
+        .collect();
+
+    let mut expanded = vec![args[0].clone()];
+    for (key, value) in &settings {
+        let arg = find_arg(&command, key)?;
+        if given
+            .iter()
+            .any(|given| given.get_id() == arg.get_id() || conflicts(&command, arg, given))
+        {
+            continue;
+        }
+        if arg.get_action().takes_values() {
+            let value = to_arg(value).map_err(|e| format!("invalid value for '{}': {}", key, e))?;
+            expanded.push(format!("--{}={}", key, value));
+        } else if *value == Value::Boolean(true) {
+            expanded.push(format!("--{}", key));
+        } else if *value != Value::Boolean(false) {
+            return Err(format!("'{}' must be true or false", key));
+        }
+    }
+    expanded.extend(args.into_iter().skip(1));
+    Ok(expanded)
+}
+
+/// `base` with `overrides` applied on top: settings for the same option,
+/// or for options conflicting with one in `overrides`, are replaced.
+fn merge(
+    command: &clap::Command,
+    base: Settings,
+    overrides: &Settings,
+) -> Result<Settings, String> {
+    let mut merged = Settings::new();
+    for (key, value) in base {
+        let arg = find_arg(command, &key)?;
+        let mut replaced = false;
+        for (override_key, _) in overrides {
+            let other = find_arg(command, override_key)?;
+            replaced |= other.get_id() == arg.get_id() || conflicts(command, arg, other);
+        }
+        if !replaced {
+            merged.push((key, value));
+        }
+    }
+    merged.extend(overrides.iter().cloned());
+    Ok(merged)
+}
+
+/// The option a setting's key names.
+fn find_arg<'a>(command: &'a clap::Command, key: &str) -> Result<&'a clap::Arg, String> {
+    command
+        .get_arguments()
+        .find(|arg| arg.get_long() == Some(key) && !matches!(key, "config" | "preset" | "help"))
+        .ok_or_else(|| format!("unknown option '{}' in config", key))
+}
+
+fn conflicts(command: &clap::Command, a: &clap::Arg, b: &clap::Arg) -> bool {
+    let conflicts_with = |a: &clap::Arg, b: &clap::Arg| {
+        command
+            .get_arg_conflicts_with(a)
+            .iter()
+            .any(|arg| arg.get_id() == b.get_id())
+    };
+    conflicts_with(a, b) || conflicts_with(b, a)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Args;
+
+    /// Parses `settings` as compression options, with `config` as the
+    /// config file.
+    fn args_with_config(name: &str, config: &str, settings: &str) -> Args {
+        let path = std::env::temp_dir().join(format!(
+            "img-compressor-{}-{}.toml",
+            name,
+            std::process::id()
+        ));
+        fs::write(&path, config).unwrap();
+        let settings = format!("--config {} {}", path.display(), settings);
+        let args = Args::from_settings("input.png", &settings);
+        fs::remove_file(&path).unwrap();
+        args.unwrap()
+    }
+
+    #[test]
+    fn parses_multi_line_arrays_and_inline_preset_tables() {
+        let config = parse(
+            "iterations = [\n    100,\n    1000, # and more\n]\n\n\
+             [preset]\nweb = { scale = 0.5, outline = \"#000000\" }\n",
+            "test",
+        )
+        .unwrap();
+
+        assert_eq!(to_arg(&config.defaults[0].1).unwrap(), "100,1000");
+        let web = config.preset("web").unwrap();
+        assert!(web.contains(&("scale".to_string(), Value::Float(0.5))));
+        assert!(web.contains(&("outline".to_string(), Value::String("#000000".to_string()))));
+    }
+
+    #[test]
+    fn preset_overrides_top_level_settings() {
+        let config = "iterations = 100\nscale = 0.5\n\n[preset.big]\nscale = 2\n";
+        let args = args_with_config("preset-overrides", config, "--preset big");
+
+        assert_eq!(args.scale, Some(2.0));
+        assert_eq!(args.iterations, vec![100]);
+    }
+
+    #[test]
+    fn command_line_overrides_preset() {
+        let config = "[preset.big]\niterations = 100\nscale = 2\n";
+        let args = args_with_config(
+            "cli-overrides",
+            config,
+            "--preset big --iterations 7 --output-size 10x10",
+        );
+
+        assert_eq!(args.iterations, vec![7]);
+        // --output-size conflicts with the preset's --scale, which is dropped
+        assert_eq!(args.scale, None);
+        assert_eq!(args.output_size.as_deref(), Some("10x10"));
+    }
+}
@@ -1,7 +1,9 @@
 use std::ops::{Add, Div, Mul, Sub};
 
 use crate::prefix_sum_matrix::{PrefixSumMatrix, Zero};
+use image::RgbImage;
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct RGB<T> {
     pub r: T,
@@ -63,36 +65,43 @@ impl Zero for RGB<u64> {
     }
 }
 
+impl Zero for u64 {
+    fn zero() -> Self {
+        0
+    }
+}
+
 pub struct ImageData {
     height: usize,
     width: usize,
     sums: PrefixSumMatrix<RGB<u64>>,
     square_sums: PrefixSumMatrix<RGB<u64>>,
+    edge_sums: Option<PrefixSumMatrix<u64>>,
 }
 
 impl ImageData {
-    pub fn new(data: &Vec<Vec<RGB<u64>>>) -> Result<Self, String> {
+    pub fn new(data: &[Vec<RGB<u64>>], edge_map: bool) -> Result<Self, String> {
         let sums = PrefixSumMatrix::new(data)?;
-        let squares = data
+        let squares: Vec<Vec<RGB<u64>>> = data
             .iter()
             .map(|row| row.iter().map(|x| x.comp_prod(*x)).collect())
             .collect();
         let square_sums = PrefixSumMatrix::new(&squares)?;
+        let edge_sums = if edge_map {
+            Some(PrefixSumMatrix::new(&edge_energy_map(data))?)
+        } else {
+            None
+        };
         Ok(Self {
             height: sums.height(),
             width: sums.width(),
             sums,
             square_sums,
+            edge_sums,
         })
     }
 
-    pub fn from_path(path: &str) -> Result<Self, String> {
-        let Ok(image) = image::open(path) else {
-            return Err(format!("Failed to open image file: {}", path));
-        };
-
-        let image_rgb = image.to_rgb8();
-
+    pub fn from_image(image_rgb: &RgbImage, edge_map: bool) -> Result<Self, String> {
         let (width, height) = image_rgb.dimensions();
         let mut data = vec![vec![RGB::zero(); width as usize]; height as usize];
 
@@ -101,7 +110,7 @@ impl ImageData {
             data[y as usize][x as usize] = rgb;
         }
 
-        Self::new(&data)
+        Self::new(&data, edge_map)
     }
 
     pub fn height(&self) -> usize {
@@ -120,6 +129,14 @@ impl ImageData {
         self.square_sums.query_sum(top_left, bottom_right)
     }
 
+    /// Sum of the squared Sobel gradient over the region, or 0 when the
+    /// edge map was not built.
+    pub fn edge_energy(&self, top_left: (usize, usize), bottom_right: (usize, usize)) -> u64 {
+        self.edge_sums
+            .as_ref()
+            .map_or(0, |edges| edges.query_sum(top_left, bottom_right))
+    }
+
     pub fn average(&self, top_left: (usize, usize), bottom_right: (usize, usize)) -> RGB<u64> {
         let (x1, y1) = top_left;
         let (x2, y2) = bottom_right;
@@ -127,6 +144,25 @@ impl ImageData {
         self.sum(top_left, bottom_right) / area
     }
 
+    /// Sum of squared differences between the pixels and the rounded down
+    /// `average` they are drawn with, over all channels:
+    /// `Σx² - 2mΣx + n·m²` per channel.
+    pub fn error(&self, top_left: (usize, usize), bottom_right: (usize, usize)) -> u64 {
+        let (x1, y1) = top_left;
+        let (x2, y2) = bottom_right;
+        let area = ((x2 - x1 + 1) * (y2 - y1 + 1)) as u64;
+
+        let mean = self.average(top_left, bottom_right);
+        let sum = self.sum(top_left, bottom_right);
+        let square_sum = self.square_sum(top_left, bottom_right);
+
+        let channel =
+            |square_sum: u64, sum: u64, mean: u64| square_sum + area * mean * mean - 2 * mean * sum;
+        channel(square_sum.r, sum.r, mean.r)
+            + channel(square_sum.g, sum.g, mean.g)
+            + channel(square_sum.b, sum.b, mean.b)
+    }
+
     pub fn variance(&self, top_left: (usize, usize), bottom_right: (usize, usize)) -> u64 {
         let (x1, y1) = top_left;
         let (x2, y2) = bottom_right;
@@ -146,3 +182,38 @@ impl ImageData {
         (variance_r + variance_g + variance_b) * area
     }
 }
+
+fn luminance(pixel: RGB<u64>) -> i64 {
+    ((299 * pixel.r + 587 * pixel.g + 114 * pixel.b) / 1000) as i64
+}
+
+/// Squared Sobel gradient magnitude of the luminance at every pixel, scaled
+/// down by the kernel gain so it is in the same units as the colour variance.
+/// Borders are handled by clamping to the nearest pixel.
+fn edge_energy_map(data: &[Vec<RGB<u64>>]) -> Vec<Vec<u64>> {
+    let height = data.len();
+    let width = data.first().map_or(0, |row| row.len());
+    let luma: Vec<Vec<i64>> = data
+        .iter()
+        .map(|row| row.iter().map(|p| luminance(*p)).collect())
+        .collect();
+
+    let at = |y: usize, x: usize, dy: isize, dx: isize| -> i64 {
+        let y = y.saturating_add_signed(dy).min(height - 1);
+        let x = x.saturating_add_signed(dx).min(width - 1);
+        luma[y][x]
+    };
+
+    let mut energy = vec![vec![0u64; width]; height];
+    for (y, row) in energy.iter_mut().enumerate() {
+        for (x, value) in row.iter_mut().enumerate() {
+            let gx = (at(y, x, -1, 1) + 2 * at(y, x, 0, 1) + at(y, x, 1, 1))
+                - (at(y, x, -1, -1) + 2 * at(y, x, 0, -1) + at(y, x, 1, -1));
+            let gy = (at(y, x, 1, -1) + 2 * at(y, x, 1, 0) + at(y, x, 1, 1))
+                - (at(y, x, -1, -1) + 2 * at(y, x, -1, 0) + at(y, x, -1, 1));
+            *value = ((gx * gx + gy * gy) / 16) as u64;
+        }
+    }
+
+    energy
+}
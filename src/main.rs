@@ -1,117 +1,430 @@
+mod animation;
+mod atomic_file;
 mod cli;
+mod compare;
+mod config;
 mod image_processor;
+mod metadata;
+mod metrics;
+mod output;
 mod prefix_sum_matrix;
 mod quad_tree;
+mod render;
+mod svg;
 mod utils;
 
-use cli::parse_args;
+use animation::{AnimationFormat, FrameTiming};
+use cli::{Args, Command, GifSchedule, OutputFormat, PngCompression, ReportFormat, parse_args};
+use image::ImageFormat;
+use image::codecs::png::CompressionType;
+use output::{ErrorKind, Event, detail, fail, status};
 use quad_tree::QuadTree;
+use std::time::Instant;
 use utils::{
-    default_output_file, ensure_valid_output_file, hex_to_rgb, load_image_data, print_step,
-    print_success, process_gif_compression, process_static_compression,
+    CaptureSchedule, EncodeOptions, QualityCheck, STDIO, StillFormat, check_overwrite,
+    default_output_file, ensure_valid_output_file, hex_to_rgb, level_output_file, load_checkpoint,
+    load_image_data, print_step, print_success, process_animated_compression, process_rd_curve,
+    process_static_compression, save_checkpoint,
 };
 
 fn main() {
-    let args = parse_args();
-
-    println!("Image Compressor");
-    println!("Input file: {}", args.input_file);
-
-    // Convert outline hex to RGB if provided
-    let outline_rgb = if let Some(outline_hex) = &args.outline {
-        match hex_to_rgb(outline_hex) {
-            Ok(rgb) => {
-                println!(
-                    "Outline color: {} -> RGB({}, {}, {})",
-                    outline_hex, rgb.r, rgb.g, rgb.b
-                );
-                Some(rgb)
-            }
-            Err(e) => {
-                eprintln!("Error parsing outline color '{}': {}", outline_hex, e);
-                std::process::exit(1);
-            }
-        }
+    match parse_args() {
+        Command::Compress(args) => compress(*args),
+        Command::Compare(args) => compare::run(&args),
+    }
+}
+
+fn compress(args: Args) {
+    let to_stdout = args.output_file.as_deref() == Some(STDIO);
+    if to_stdout {
+        output::use_stderr();
+    }
+    let started = Instant::now();
+
+    status!("Image Compressor");
+    if args.input_file == STDIO {
+        status!("Input file: stdin");
     } else {
-        None
+        status!("Input file: {}", args.input_file);
+    }
+
+    let render_options = args
+        .render_options()
+        .unwrap_or_else(|e| fail(ErrorKind::Usage, format!("Error: {}", e)));
+    let print_color = |name: &str, hex: &Option<String>| {
+        if let Some(hex) = hex
+            && let Ok(rgb) = hex_to_rgb(hex)
+        {
+            status!("{}: {} -> RGB({}, {}, {})", name, hex, rgb.r, rgb.g, rgb.b);
+        }
+    };
+    print_color("Outline color", &args.outline);
+    print_color("Outline gradient end", &args.outline_gradient);
+    print_color("Background color", &args.background);
+
+    let split_options = args
+        .split_options()
+        .unwrap_or_else(|e| fail(ErrorKind::Usage, format!("Error: {}", e)));
+
+    let mut levels = args.iterations.clone();
+    levels.sort_unstable();
+    levels.dedup();
+    let iterations = *levels
+        .last()
+        .expect("clap requires at least one iteration count");
+
+    // GIF output is requested by either a capture delta or a capture schedule
+    let capture = match (args.gif_schedule, args.gif_delta) {
+        (Some(GifSchedule::Exponential), None) => Some(CaptureSchedule::Doubling),
+        (Some(GifSchedule::Exponential), Some(_)) => {
+            fail(
+                ErrorKind::Usage,
+                "Error: --gif-delta only applies to --gif-schedule linear",
+            );
+        }
+        (_, Some(delta)) => Some(CaptureSchedule::Every(delta)),
+        (Some(GifSchedule::Linear), None) => {
+            fail(
+                ErrorKind::Usage,
+                "Error: --gif-schedule linear requires --gif-delta",
+            );
+        }
+        (None, None) => None,
     };
 
-    // Handle output file validation
-    let output_file = if let Some(user_output) = &args.output_file {
-        match ensure_valid_output_file(user_output, &args.input_file, args.gif_delta.is_some()) {
+    if capture.is_none() && (args.frame_delay.is_some() || args.hold_final.is_some()) {
+        fail(
+            ErrorKind::Usage,
+            "Error: --frame-delay and --hold-final only apply to GIF output",
+        );
+    }
+
+    if levels.len() > 1 && args.rd_curve.is_some() {
+        fail(
+            ErrorKind::Usage,
+            "Error: --rd-curve takes a single iteration count",
+        );
+    }
+
+    if levels.len() > 1 && capture.is_some() {
+        fail(
+            ErrorKind::Usage,
+            "Error: multiple iteration counts cannot be combined with GIF output",
+        );
+    }
+
+    let format = args.format.map(|format| match format {
+        OutputFormat::Png => StillFormat::Raster(ImageFormat::Png),
+        OutputFormat::Jpeg => StillFormat::Raster(ImageFormat::Jpeg),
+        OutputFormat::Webp => StillFormat::Raster(ImageFormat::WebP),
+        OutputFormat::Bmp => StillFormat::Raster(ImageFormat::Bmp),
+        OutputFormat::Tiff => StillFormat::Raster(ImageFormat::Tiff),
+        OutputFormat::Qoi => StillFormat::Raster(ImageFormat::Qoi),
+        OutputFormat::Gif => StillFormat::Raster(ImageFormat::Gif),
+        OutputFormat::Svg => StillFormat::Svg,
+    });
+
+    if to_stdout {
+        if format.is_none() {
+            fail(
+                ErrorKind::Usage,
+                "Error: writing to stdout requires --format",
+            );
+        }
+        if levels.len() > 1 {
+            fail(
+                ErrorKind::Usage,
+                "Error: only a single iteration count can be written to stdout",
+            );
+        }
+        if capture.is_some() {
+            fail(
+                ErrorKind::Usage,
+                "Error: animations can't be written to stdout",
+            );
+        }
+        if args.report.is_some() {
+            fail(ErrorKind::Usage, "Error: --report needs an output file");
+        }
+    }
+
+    if capture.is_some()
+        && (format.is_some() || args.jpeg_quality.is_some() || args.png_compression.is_some())
+    {
+        fail(
+            ErrorKind::Usage,
+            "Error: --format, --jpeg-quality and --png-compression only apply to still images",
+        );
+    }
+
+    if args.input_file == STDIO && args.output_file.is_none() {
+        fail(
+            ErrorKind::Usage,
+            "Error: reading from stdin requires --output-file",
+        );
+    }
+
+    // Handle output file validation. An explicit format is taken at its word,
+    // whatever the extension.
+    let output_file = if to_stdout || format.is_some() {
+        args.output_file.clone()
+    } else if let Some(user_output) = &args.output_file {
+        match ensure_valid_output_file(user_output, &args.input_file, capture.is_some()) {
             Ok(validated_path) => {
                 if validated_path != *user_output {
-                    println!(
+                    status!(
                         "Output file corrected: {} -> {}",
-                        user_output, validated_path
+                        user_output,
+                        validated_path
                     );
                 }
-                validated_path
-            }
-            Err(e) => {
-                eprintln!("Error validating output file: {}", e);
-                std::process::exit(1);
+                Some(validated_path)
             }
+            Err(e) => fail(
+                ErrorKind::Usage,
+                format!("Error validating output file: {}", e),
+            ),
         }
     } else {
-        match default_output_file(
-            &args.input_file,
-            args.gif_delta.is_some(),
-            args.iterations,
-            args.outline.is_some(),
-            args.gif_delta,
-        ) {
-            Ok(default_path) => default_path,
-            Err(e) => {
-                eprintln!("Error generating default output file: {}", e);
-                std::process::exit(1);
+        None
+    };
+
+    // One output per iteration level
+    let outputs: Vec<(u32, String)> = levels
+        .iter()
+        .map(|&level| {
+            let path = match &output_file {
+                Some(path) if levels.len() > 1 => level_output_file(path, level),
+                Some(path) => Ok(path.clone()),
+                None => default_output_file(
+                    &args.input_file,
+                    level,
+                    args.outline.is_some(),
+                    capture,
+                    format,
+                ),
+            };
+            match path {
+                Ok(path) => (level, path),
+                Err(e) => fail(
+                    ErrorKind::Usage,
+                    format!("Error generating output file: {}", e),
+                ),
             }
-        }
+        })
+        .collect();
+
+    let mut encoding = EncodeOptions {
+        format,
+        jpeg_quality: args.jpeg_quality,
+        png_compression: match args.png_compression {
+            None | Some(PngCompression::Fast) => CompressionType::Fast,
+            Some(PngCompression::Default) => CompressionType::Default,
+            Some(PngCompression::Best) => CompressionType::Best,
+        },
+        ..EncodeOptions::default()
     };
 
-    println!("Output file: {}", output_file);
-    println!();
+    // Encoder settings have to match the format they tune
+    let still_format = format.or_else(|| StillFormat::from_path(&outputs[0].1).ok());
+    if args.jpeg_quality.is_some() && still_format != Some(StillFormat::Raster(ImageFormat::Jpeg)) {
+        fail(
+            ErrorKind::Usage,
+            "Error: --jpeg-quality only applies to JPEG output",
+        );
+    }
+    if args.png_compression.is_some() && still_format != Some(StillFormat::Raster(ImageFormat::Png))
+    {
+        fail(
+            ErrorKind::Usage,
+            "Error: --png-compression only applies to PNG output",
+        );
+    }
+
+    // Every file this run writes, checked before any of the work is done
+    let mut written = Vec::new();
+    for (_, output_file) in &outputs {
+        match AnimationFormat::from_path(output_file) {
+            // How many frames there will be isn't known yet, so any file
+            // named like a frame counts
+            Some(AnimationFormat::PngSequence) if capture.is_some() => {
+                written.extend(animation::existing_sequence_frames(output_file));
+            }
+            _ => written.push(output_file.clone()),
+        }
+        if args.report == Some(ReportFormat::Json) {
+            written.push(metrics::report_file(output_file));
+        }
+    }
+    written.extend(args.rd_curve.clone());
+    for file in &written {
+        if let Err(e) = check_overwrite(file, &args.input_file, args.force) {
+            fail(ErrorKind::Output, format!("Error: {}", e));
+        }
+    }
+    // Checkpoints are meant to be updated in place, just never over the input
+    if let Some(checkpoint_file) = &args.checkpoint
+        && let Err(e) = check_overwrite(checkpoint_file, &args.input_file, true)
+    {
+        fail(ErrorKind::Checkpoint, format!("Error: {}", e));
+    }
+
+    if let Some(weight) = args.edge_weight {
+        status!("Edge weight: {}", weight);
+    }
+    if let Some(target) = args.target_psnr {
+        status!("Target PSNR: {} dB", target);
+    }
+
+    for (_, output_file) in &outputs {
+        status!("Output file: {}", display_path(output_file));
+    }
+    status!();
 
     // Load image data
-    let data = match load_image_data(&args.input_file) {
-        Ok(data) => data,
-        Err(e) => {
-            eprintln!("Error processing image: {}", e);
-            std::process::exit(1);
+    let (data, original, original_bytes, metadata) =
+        load_image_data(&args.input_file, args.edge_weight.is_some())
+            .unwrap_or_else(|e| fail(ErrorKind::Input, format!("Error processing image: {}", e)));
+    detail!(
+        "Image size: {}x{}, {} bytes",
+        original.width(),
+        original.height(),
+        original_bytes
+    );
+    if args.min_leaf_size.is_some() || args.max_leaf_size.is_some() {
+        detail!(
+            "Leaf size: {} to {} pixels",
+            args.min_leaf_size
+                .map_or("1".to_string(), |size| size.to_string()),
+            args.max_leaf_size
+                .map_or("unlimited".to_string(), |size| size.to_string())
+        );
+    }
+    if !args.strip_metadata {
+        let kept = metadata.describe();
+        if !kept.is_empty() {
+            detail!("Metadata kept: {}", kept.join(", "));
         }
+        encoding.metadata = metadata;
+    }
+    Event::new("loaded")
+        .string("input_file", &args.input_file)
+        .integer("width", original.width() as u64)
+        .integer("height", original.height() as u64)
+        .integer("bytes", original_bytes)
+        .emit();
+
+    let quality = QualityCheck {
+        original,
+        original_bytes,
+        json_report: args.report == Some(ReportFormat::Json),
     };
 
     // Initialize quad tree
-    print_step("Initializing quad tree");
-    let mut quad_tree = QuadTree::new(data);
-    print_success();
+    let mut quad_tree = match &args.resume {
+        Some(checkpoint_file) => match load_checkpoint(checkpoint_file, data, split_options) {
+            Ok(quad_tree) => {
+                status!("Resuming after {} iterations", quad_tree.splits());
+                quad_tree
+            }
+            Err(e) => fail(
+                ErrorKind::Checkpoint,
+                format!("Error resuming from checkpoint: {}", e),
+            ),
+        },
+        None => {
+            print_step("Initializing quad tree");
+            let quad_tree = QuadTree::new(data, split_options);
+            print_success();
+            quad_tree
+        }
+    };
 
     // Process based on whether GIF output is requested
-    match args.gif_delta {
-        Some(delta) => {
-            if let Err(e) = process_gif_compression(
+    match capture {
+        Some(schedule) => {
+            let timing = FrameTiming {
+                frame_delay: args.frame_delay,
+                hold_final: args.hold_final,
+            };
+            let output_file = &outputs[0].1;
+            let format = AnimationFormat::from_path(output_file).unwrap_or(AnimationFormat::Gif);
+            if let Err(e) = process_animated_compression(
                 &mut quad_tree,
-                args.iterations,
-                delta,
-                outline_rgb,
-                &output_file,
+                iterations,
+                schedule,
+                timing,
+                format,
+                &render_options,
+                &quality,
+                output_file,
+                args.force,
             ) {
-                eprintln!("Error during GIF compression: {}", e);
-                std::process::exit(1);
+                fail(
+                    ErrorKind::Output,
+                    format!("Error during {} compression: {}", format.name(), e),
+                );
             }
         }
         None => {
+            if let Some(csv_file) = &args.rd_curve
+                && let Err(e) = process_rd_curve(&mut quad_tree, iterations, csv_file, args.force)
+            {
+                fail(
+                    ErrorKind::Output,
+                    format!("Error recording rate-distortion curve: {}", e),
+                );
+            }
             if let Err(e) = process_static_compression(
                 &mut quad_tree,
-                args.iterations,
-                outline_rgb,
-                &output_file,
+                &outputs,
+                &render_options,
+                &encoding,
+                &quality,
+                args.force,
             ) {
-                eprintln!("Error during static compression: {}", e);
-                std::process::exit(1);
+                fail(
+                    ErrorKind::Output,
+                    format!("Error during static compression: {}", e),
+                );
             }
         }
     }
 
-    println!("Compression complete! Output saved to: {}", output_file);
+    if let Some(checkpoint_file) = &args.checkpoint
+        && let Err(e) = save_checkpoint(&quad_tree, checkpoint_file)
+    {
+        fail(ErrorKind::Checkpoint, e);
+    }
+
+    if let [(_, output_file)] = outputs.as_slice() {
+        status!(
+            "Compression complete! Output saved to: {}",
+            display_path(output_file)
+        );
+    } else {
+        status!("Compression complete! Outputs saved to:");
+        for (_, output_file) in &outputs {
+            status!("  {}", output_file);
+        }
+    }
+    detail!("Finished in {:.2}s", started.elapsed().as_secs_f64());
+
+    let output_files: Vec<String> = outputs
+        .iter()
+        .map(|(_, output_file)| metrics::json_string(output_file))
+        .collect();
+    Event::new("finished")
+        .raw("outputs", &format!("[{}]", output_files.join(",")))
+        .integer("iterations", quad_tree.splits() as u64)
+        .integer("leaves", quad_tree.leaf_count() as u64)
+        .number("mse", quad_tree.mse())
+        .number("psnr", quad_tree.psnr())
+        .integer("elapsed_ms", started.elapsed().as_millis() as u64)
+        .emit();
+}
+
+/// How an output path is shown in status messages.
+fn display_path(path: &str) -> &str {
+    if path == STDIO { "stdout" } else { path }
 }
@@ -0,0 +1,219 @@
+use image::metadata::Orientation;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader, ImageResult};
+use std::io::{BufRead, Cursor, Seek};
+use std::time::SystemTime;
+
+/// EXIF tag holding the orientation, and the type of its value.
+const ORIENTATION_TAG: u16 = 0x112;
+const SHORT: u16 = 3;
+
+/// Largest EXIF block that fits in a JPEG APP1 segment, after the length
+/// and the `Exif\0\0` header.
+const MAX_JPEG_EXIF: usize = u16::MAX as usize - 8;
+
+/// What an input carries besides its pixels.
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    /// Colour profile of the pixel values.
+    pub icc_profile: Option<Vec<u8>>,
+    /// Raw EXIF block, starting with its TIFF header. The orientation is
+    /// reset to upright, since the decoded pixels have already been turned.
+    pub exif: Option<Vec<u8>>,
+    /// Modification time of the input file.
+    pub modified: Option<SystemTime>,
+}
+
+impl Metadata {
+    /// Names of what is present, for verbose output.
+    pub fn describe(&self) -> Vec<&'static str> {
+        [
+            (self.icc_profile.is_some(), "ICC profile"),
+            (self.exif.is_some(), "EXIF"),
+            (self.modified.is_some(), "timestamp"),
+        ]
+        .into_iter()
+        .filter_map(|(present, name)| present.then_some(name))
+        .collect()
+    }
+}
+
+/// Opens the image at `path` the right way up, with its metadata.
+pub fn open(path: &str) -> ImageResult<(DynamicImage, Metadata)> {
+    let (image, mut metadata) = decode(ImageReader::open(path)?)?;
+    metadata.modified = std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok();
+    Ok((image, metadata))
+}
+
+/// Decodes an encoded image the right way up, guessing its format from the
+/// first bytes.
+pub fn load_from_memory(encoded: &[u8]) -> ImageResult<(DynamicImage, Metadata)> {
+    decode(ImageReader::new(Cursor::new(encoded)).with_guessed_format()?)
+}
+
+/// Decodes `reader` and applies its EXIF orientation. Metadata that can't
+/// be read is left out rather than failing the decode.
+fn decode(reader: ImageReader<impl BufRead + Seek>) -> ImageResult<(DynamicImage, Metadata)> {
+    let mut decoder = reader.into_decoder()?;
+    let icc_profile = decoder.icc_profile().ok().flatten();
+    let exif = decoder.exif_metadata().ok().flatten();
+    let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+    let metadata = Metadata {
+        icc_profile,
+        exif: exif.map(|mut exif| {
+            reset_orientation(&mut exif);
+            exif
+        }),
+        modified: None,
+    };
+    Ok((image, metadata))
+}
+
+/// Sets the orientation tag in the first IFD of `exif` to upright, if it
+/// has one.
+fn reset_orientation(exif: &mut [u8]) {
+    let big_endian = match exif.get(..4) {
+        Some([0x49, 0x49, 42, 0]) => false,
+        Some([0x4d, 0x4d, 0, 42]) => true,
+        _ => return,
+    };
+    let read_u16 = |exif: &[u8], at: usize| {
+        let bytes = exif.get(at..at + 2)?.try_into().ok()?;
+        Some(if big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    };
+    let ifd = match exif.get(4..8).and_then(|bytes| bytes.try_into().ok()) {
+        Some(bytes) if big_endian => u32::from_be_bytes(bytes) as usize,
+        Some(bytes) => u32::from_le_bytes(bytes) as usize,
+        None => return,
+    };
+    let Some(entries) = read_u16(exif, ifd) else {
+        return;
+    };
+
+    for entry in (0..entries as usize).map(|i| ifd + 2 + i * 12) {
+        if read_u16(exif, entry) == Some(ORIENTATION_TAG)
+            && read_u16(exif, entry + 2) == Some(SHORT)
+        {
+            let upright = if big_endian {
+                1u16.to_be_bytes()
+            } else {
+                1u16.to_le_bytes()
+            };
+            if let Some(value) = exif.get_mut(entry + 8..entry + 10) {
+                value.copy_from_slice(&upright);
+            }
+            return;
+        }
+    }
+}
+
+/// `encoded` with `exif` added: as an APP1 segment after the JFIF header
+/// of a JPEG, or as an eXIf chunk ahead of the image data of a PNG. Other
+/// formats, and blocks too large for a JPEG segment, are left unchanged.
+pub fn with_exif(mut encoded: Vec<u8>, format: ImageFormat, exif: &[u8]) -> Vec<u8> {
+    match format {
+        ImageFormat::Jpeg if exif.len() <= MAX_JPEG_EXIF => {
+            // After SOI, and after the APP0 segment if the encoder wrote one
+            let mut at = 2;
+            if encoded.get(2..4) == Some(&[0xff, 0xe0]) {
+                let Some(length) = encoded.get(4..6) else {
+                    return encoded;
+                };
+                at += 2 + u16::from_be_bytes([length[0], length[1]]) as usize;
+            }
+            let mut segment = vec![0xff, 0xe1];
+            segment.extend_from_slice(&((exif.len() + 8) as u16).to_be_bytes());
+            segment.extend_from_slice(b"Exif\0\0");
+            segment.extend_from_slice(exif);
+            encoded.splice(at..at, segment);
+        }
+        ImageFormat::Png => {
+            // Past the signature, then chunk by chunk up to the first IDAT
+            let mut at = 8;
+            while let Some(header) = encoded.get(at..at + 8) {
+                if &header[4..] == b"IDAT" {
+                    let mut chunk = (exif.len() as u32).to_be_bytes().to_vec();
+                    chunk.extend_from_slice(b"eXIf");
+                    chunk.extend_from_slice(exif);
+                    let crc = crc32fast::hash(&chunk[4..]);
+                    chunk.extend_from_slice(&crc.to_be_bytes());
+                    encoded.splice(at..at, chunk);
+                    break;
+                }
+                let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+                at += 12 + length as usize;
+            }
+        }
+        _ => {}
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbImage;
+
+    /// A little-endian EXIF block whose only entry is `orientation`.
+    fn exif_block(orientation: u16) -> Vec<u8> {
+        let mut exif = vec![0x49, 0x49, 42, 0, 8, 0, 0, 0, 1, 0];
+        exif.extend_from_slice(&ORIENTATION_TAG.to_le_bytes());
+        exif.extend_from_slice(&SHORT.to_le_bytes());
+        exif.extend_from_slice(&1u32.to_le_bytes());
+        exif.extend_from_slice(&orientation.to_le_bytes());
+        exif.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+        exif
+    }
+
+    fn encode(format: ImageFormat, exif: &[u8]) -> Vec<u8> {
+        let image = RgbImage::from_fn(4, 2, |x, y| image::Rgb([x as u8 * 60, y as u8 * 120, 90]));
+        let mut encoded = Cursor::new(Vec::new());
+        image.write_to(&mut encoded, format).unwrap();
+        with_exif(encoded.into_inner(), format, exif)
+    }
+
+    fn read_exif(encoded: &[u8]) -> (Option<Vec<u8>>, Orientation) {
+        let mut decoder = ImageReader::new(Cursor::new(encoded))
+            .with_guessed_format()
+            .unwrap()
+            .into_decoder()
+            .unwrap();
+        let exif = decoder.exif_metadata().unwrap();
+        (exif, decoder.orientation().unwrap())
+    }
+
+    #[test]
+    fn exif_survives_with_the_orientation_reset() {
+        for format in [ImageFormat::Jpeg, ImageFormat::Png] {
+            let rotated = exif_block(6);
+            assert_eq!(
+                read_exif(&encode(format, &rotated)).1,
+                Orientation::Rotate90
+            );
+
+            let mut exif = rotated.clone();
+            reset_orientation(&mut exif);
+            assert_eq!(exif, exif_block(1));
+            let (read, orientation) = read_exif(&encode(format, &exif));
+            assert_eq!(read, Some(exif));
+            assert_eq!(orientation, Orientation::NoTransforms);
+        }
+    }
+
+    #[test]
+    fn decoding_applies_the_orientation() {
+        for format in [ImageFormat::Jpeg, ImageFormat::Png] {
+            let (image, metadata) = load_from_memory(&encode(format, &exif_block(6))).unwrap();
+            assert_eq!((image.width(), image.height()), (2, 4));
+            assert_eq!(metadata.exif, Some(exif_block(1)));
+        }
+    }
+}
@@ -0,0 +1,278 @@
+use crate::output::{Event, status};
+use crate::quad_tree::QuadTree;
+use crate::render::RenderOptions;
+use image::RgbImage;
+use std::path::Path;
+
+/// Bits stored per leaf colour in the size estimate, on top of one bit per
+/// node saying whether it is split.
+const LEAF_COLOR_BITS: u64 = 24;
+
+/// Side of the square windows SSIM is computed over, and the step between
+/// them.
+const SSIM_WINDOW: u32 = 8;
+const SSIM_STRIDE: u32 = 4;
+
+const CHANNELS: [&str; 3] = ["red", "green", "blue"];
+
+/// How close the compressed image is to the original, and how small the
+/// tree behind it is.
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    pub iterations: u32,
+    /// Mean squared error per channel.
+    pub mse: [f64; 3],
+    /// Mean squared error over all channels.
+    pub mse_total: f64,
+    /// Mean structural similarity of the luma, from -1 to 1.
+    pub ssim: f64,
+    pub leaves: usize,
+    /// Estimated size of the tree: a split flag per node and a colour per leaf.
+    pub tree_bits: u64,
+    /// Size of the input file.
+    pub original_bytes: u64,
+}
+
+impl Metrics {
+    /// Compares `original` with the tree's leaves at their average colours.
+    /// Outlines, shapes and output scaling only change how the result is
+    /// drawn, so they are left out.
+    pub fn measure(quad_tree: &QuadTree, original: &RgbImage, original_bytes: u64) -> Self {
+        let compressed = quad_tree.render_rgb(&RenderOptions::default());
+        let mse = channel_mse(original, &compressed);
+
+        let leaves = quad_tree.leaf_count();
+        Self {
+            iterations: quad_tree.splits(),
+            mse,
+            mse_total: mse.iter().sum::<f64>() / 3.0,
+            ssim: ssim(original, &compressed),
+            leaves,
+            tree_bits: tree_bits(quad_tree.node_count(), leaves),
+            original_bytes,
+        }
+    }
+
+    pub fn psnr(&self) -> [f64; 3] {
+        self.mse.map(psnr)
+    }
+
+    pub fn psnr_total(&self) -> f64 {
+        psnr(self.mse_total)
+    }
+
+    /// Original file size over the estimated tree size.
+    pub fn compression_ratio(&self) -> f64 {
+        self.original_bytes as f64 * 8.0 / self.tree_bits as f64
+    }
+
+    pub fn print(&self) {
+        status!("Quality after {} iterations:", self.iterations);
+        print_similarity(self.mse, self.ssim);
+        status!(
+            "  {} leaves, ~{} bytes as a tree vs {} bytes original ({:.1}:1)",
+            self.leaves,
+            self.tree_bits.div_ceil(8),
+            self.original_bytes,
+            self.compression_ratio()
+        );
+    }
+
+    /// The metrics as a `quality` event of the JSON output mode.
+    pub fn event(&self, output_file: &str) -> Event {
+        Event::new("quality")
+            .string("output_file", output_file)
+            .integer("iterations", self.iterations as u64)
+            .raw("mse", &channels(self.mse, self.mse_total))
+            .raw("psnr", &channels(self.psnr(), self.psnr_total()))
+            .number("ssim", self.ssim)
+            .integer("leaves", self.leaves as u64)
+            .integer("tree_bits", self.tree_bits)
+            .integer("original_bytes", self.original_bytes)
+            .number("compression_ratio", self.compression_ratio())
+    }
+
+    pub fn to_json(&self, output_file: &str) -> String {
+        let channels = |values: [f64; 3]| {
+            CHANNELS
+                .iter()
+                .zip(values)
+                .map(|(channel, value)| format!("\"{}\": {}", channel, json_number(value)))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        format!(
+            concat!(
+                "{{\n",
+                "  \"output_file\": {},\n",
+                "  \"iterations\": {},\n",
+                "  \"mse\": {{{}, \"total\": {}}},\n",
+                "  \"psnr\": {{{}, \"total\": {}}},\n",
+                "  \"ssim\": {},\n",
+                "  \"leaves\": {},\n",
+                "  \"tree_bits\": {},\n",
+                "  \"original_bytes\": {},\n",
+                "  \"compression_ratio\": {}\n",
+                "}}\n"
+            ),
+            json_string(output_file),
+            self.iterations,
+            channels(self.mse),
+            json_number(self.mse_total),
+            channels(self.psnr()),
+            json_number(self.psnr_total()),
+            json_number(self.ssim),
+            self.leaves,
+            self.tree_bits,
+            self.original_bytes,
+            json_number(self.compression_ratio())
+        )
+    }
+}
+
+/// Estimated size in bits of a tree with `node_count` nodes, `leaves` of
+/// them leaves.
+pub fn tree_bits(node_count: usize, leaves: usize) -> u64 {
+    node_count as u64 + leaves as u64 * LEAF_COLOR_BITS
+}
+
+/// Peak signal to noise ratio in dB, infinite for an exact match.
+pub fn psnr(mse: f64) -> f64 {
+    10.0 * (255.0 * 255.0 / mse).log10()
+}
+
+/// Mean squared error of each channel between two images of the same size.
+pub fn channel_mse(a: &RgbImage, b: &RgbImage) -> [f64; 3] {
+    let pixels = (a.width() as u64 * a.height() as u64) as f64;
+
+    let mut squared_errors = [0u64; 3];
+    for (a, b) in a.pixels().zip(b.pixels()) {
+        for (channel, error) in squared_errors.iter_mut().enumerate() {
+            let diff = a[channel] as i64 - b[channel] as i64;
+            *error += (diff * diff) as u64;
+        }
+    }
+    squared_errors.map(|error| error as f64 / pixels)
+}
+
+/// A JSON object with a field per channel and the total.
+fn channels(values: [f64; 3], total: f64) -> String {
+    let fields: Vec<String> = CHANNELS
+        .iter()
+        .zip(values)
+        .map(|(channel, value)| format!("\"{}\":{}", channel, json_number(value)))
+        .collect();
+    format!("{{{},\"total\":{}}}", fields.join(","), json_number(total))
+}
+
+/// The `similarity` event of `compare`, with the same fields as
+/// `print_similarity` prints.
+pub fn similarity_event(mse: [f64; 3], ssim: f64) -> Event {
+    let mse_total = mse.iter().sum::<f64>() / 3.0;
+    Event::new("similarity")
+        .raw("mse", &channels(mse, mse_total))
+        .raw("psnr", &channels(mse.map(psnr), psnr(mse_total)))
+        .number("ssim", ssim)
+}
+
+/// Prints the error of each channel and in total, and the SSIM.
+pub fn print_similarity(mse: [f64; 3], ssim: f64) {
+    for (i, channel) in CHANNELS.iter().enumerate() {
+        status!(
+            "  {:<6} MSE {:>9.3}  PSNR {:>6.2} dB",
+            channel,
+            mse[i],
+            psnr(mse[i])
+        );
+    }
+    let mse_total = mse.iter().sum::<f64>() / 3.0;
+    status!(
+        "  total  MSE {:>9.3}  PSNR {:>6.2} dB",
+        mse_total,
+        psnr(mse_total)
+    );
+    status!("  SSIM {:.4}", ssim);
+}
+
+/// Mean SSIM of the luma over windows of `SSIM_WINDOW` pixels, or of the
+/// whole image if it is smaller than one window.
+pub fn ssim(a: &RgbImage, b: &RgbImage) -> f64 {
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+    let luma = |image: &RgbImage| -> Vec<f64> {
+        image
+            .pixels()
+            .map(|p| 0.299 * p[0] as f64 + 0.587 * p[1] as f64 + 0.114 * p[2] as f64)
+            .collect()
+    };
+    let (luma_a, luma_b) = (luma(a), luma(b));
+
+    let (width, height) = a.dimensions();
+    let window_width = SSIM_WINDOW.min(width);
+    let window_height = SSIM_WINDOW.min(height);
+    let n = (window_width * window_height) as f64;
+
+    let mut total = 0.0;
+    let mut windows = 0;
+    for top in (0..=height - window_height).step_by(SSIM_STRIDE as usize) {
+        for left in (0..=width - window_width).step_by(SSIM_STRIDE as usize) {
+            let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) =
+                (0.0, 0.0, 0.0, 0.0, 0.0);
+            for y in top..top + window_height {
+                let row = (y * width) as usize;
+                for x in left..left + window_width {
+                    let (pa, pb) = (luma_a[row + x as usize], luma_b[row + x as usize]);
+                    sum_a += pa;
+                    sum_b += pb;
+                    sum_aa += pa * pa;
+                    sum_bb += pb * pb;
+                    sum_ab += pa * pb;
+                }
+            }
+            let (mean_a, mean_b) = (sum_a / n, sum_b / n);
+            let var_a = sum_aa / n - mean_a * mean_a;
+            let var_b = sum_bb / n - mean_b * mean_b;
+            let covariance = sum_ab / n - mean_a * mean_b;
+
+            total += ((2.0 * mean_a * mean_b + C1) * (2.0 * covariance + C2))
+                / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2));
+            windows += 1;
+        }
+    }
+
+    total / windows as f64
+}
+
+/// JSON has no infinity, so exact matches report a PSNR of `null`.
+pub fn json_number(value: f64) -> String {
+    if value.is_finite() {
+        format!("{}", (value * 10000.0).round() / 10000.0)
+    } else {
+        "null".to_string()
+    }
+}
+
+pub fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+/// The report written next to `output_file`: the same path with a `.json`
+/// extension.
+pub fn report_file(output_file: &str) -> String {
+    Path::new(output_file)
+        .with_extension("json")
+        .to_string_lossy()
+        .into_owned()
+}
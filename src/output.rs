@@ -0,0 +1,174 @@
+use crate::metrics::{json_number, json_string};
+use std::fmt::{self, Display};
+use std::io::{self, IsTerminal, Write};
+use std::sync::OnceLock;
+
+/// How status is reported on stdout.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputMode {
+    /// Nothing but errors, on stderr.
+    Quiet,
+    #[default]
+    Normal,
+    /// Normal output plus details such as image size and timings.
+    Verbose,
+    /// One JSON event per line instead of text.
+    Json,
+}
+
+static MODE: OnceLock<OutputMode> = OnceLock::new();
+
+/// Sets the mode for the rest of the run. Only the first call has an effect.
+pub fn set_mode(mode: OutputMode) {
+    let _ = MODE.set(mode);
+}
+
+static TO_STDERR: OnceLock<bool> = OnceLock::new();
+
+/// Sends status and events to stderr for the rest of the run, leaving stdout
+/// to the image. Must be called before anything is printed.
+pub fn use_stderr() {
+    let _ = TO_STDERR.set(true);
+}
+
+fn to_stderr() -> bool {
+    TO_STDERR.get().copied().unwrap_or(false)
+}
+
+/// Writes status text to stdout, or to stderr after `use_stderr`.
+pub fn print(args: fmt::Arguments) {
+    if to_stderr() {
+        let mut stderr = io::stderr().lock();
+        let _ = stderr.write_fmt(args);
+        let _ = stderr.flush();
+    } else {
+        let mut stdout = io::stdout().lock();
+        let _ = stdout.write_fmt(args);
+        let _ = stdout.flush();
+    }
+}
+
+pub fn mode() -> OutputMode {
+    MODE.get().copied().unwrap_or_default()
+}
+
+/// Whether human-readable status is printed.
+pub fn text() -> bool {
+    matches!(mode(), OutputMode::Normal | OutputMode::Verbose)
+}
+
+pub fn verbose() -> bool {
+    mode() == OutputMode::Verbose
+}
+
+/// `println!` for status text, silent in quiet and JSON mode.
+macro_rules! status {
+    () => {
+        $crate::output::status!("")
+    };
+    ($($arg:tt)*) => {
+        if $crate::output::text() {
+            $crate::output::print(format_args!("{}\n", format_args!($($arg)*)));
+        }
+    };
+}
+
+/// `println!` for details only shown with `--verbose`.
+macro_rules! detail {
+    ($($arg:tt)*) => {
+        if $crate::output::verbose() {
+            $crate::output::print(format_args!("{}\n", format_args!($($arg)*)));
+        }
+    };
+}
+
+pub(crate) use {detail, status};
+
+/// A line of newline-delimited JSON, printed only in JSON mode.
+pub struct Event {
+    line: String,
+}
+
+impl Event {
+    pub fn new(name: &str) -> Self {
+        Self {
+            line: format!("{{\"event\":{}", json_string(name)),
+        }
+    }
+
+    /// Adds a field whose value is already JSON.
+    pub fn raw(mut self, name: &str, value: &str) -> Self {
+        self.line
+            .push_str(&format!(",{}:{}", json_string(name), value));
+        self
+    }
+
+    pub fn string(self, name: &str, value: &str) -> Self {
+        self.raw(name, &json_string(value))
+    }
+
+    pub fn number(self, name: &str, value: f64) -> Self {
+        self.raw(name, &json_number(value))
+    }
+
+    pub fn integer(self, name: &str, value: u64) -> Self {
+        self.raw(name, &value.to_string())
+    }
+
+    pub fn emit(self) {
+        if mode() == OutputMode::Json {
+            print(format_args!("{}}}\n", self.line));
+        }
+    }
+}
+
+/// What went wrong, as reported by the `kind` of a JSON error event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// Invalid or inconsistent command line options.
+    Usage,
+    /// The input image could not be read.
+    Input,
+    /// An output file could not be written.
+    Output,
+    /// A checkpoint could not be read or written.
+    Checkpoint,
+}
+
+impl ErrorKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorKind::Usage => "usage",
+            ErrorKind::Input => "input",
+            ErrorKind::Output => "output",
+            ErrorKind::Checkpoint => "checkpoint",
+        }
+    }
+}
+
+/// Reports `message` as an error event in JSON mode, or on stderr
+/// otherwise, and exits.
+pub fn fail(kind: ErrorKind, message: impl Display) -> ! {
+    if mode() == OutputMode::Json {
+        Event::new("error")
+            .string("kind", kind.name())
+            .string("message", &message.to_string())
+            .emit();
+    } else {
+        eprintln!("{}", message);
+    }
+    std::process::exit(1);
+}
+
+/// Whether status goes to a terminal that can redraw a progress bar in
+/// place.
+pub fn interactive() -> bool {
+    static INTERACTIVE: OnceLock<bool> = OnceLock::new();
+    *INTERACTIVE.get_or_init(|| {
+        if to_stderr() {
+            io::stderr().is_terminal()
+        } else {
+            io::stdout().is_terminal()
+        }
+    })
+}
@@ -17,7 +17,7 @@ impl<T> PrefixSumMatrix<T>
 where
     T: Add<Output = T> + Sub<Output = T> + Zero + Clone + Copy,
 {
-    pub fn new(matrix: &Vec<Vec<T>>) -> Result<Self, String> {
+    pub fn new(matrix: &[Vec<T>]) -> Result<Self, String> {
         let height = matrix.len();
         let width = match matrix.first() {
             Some(row) => row.len(),
@@ -1,20 +1,61 @@
 use crate::image_processor::{ImageData, RGB};
+use crate::metrics::psnr;
+use crate::render::{ColorMode, LeafShape, RenderOptions, color_map};
 use image::{ImageBuffer, Pixel, Rgb, RgbImage, Rgba, RgbaImage};
 use std::cmp::Ordering;
 use std::collections::BinaryHeap;
 use std::collections::VecDeque;
-
-struct NodeChildren {
-    top_left_idx: usize,
-    top_right_idx: usize,
-    bottom_left_idx: usize,
-    bottom_right_idx: usize,
-}
+use std::io::{self, Read, Write};
+use std::ops::Range;
 
 struct Node {
     top_left: (usize, usize),
     bottom_right: (usize, usize),
-    children: Option<NodeChildren>,
+    /// Children are pushed together, so they occupy a contiguous range of
+    /// node indices: four for a regular split, two for a one pixel strip.
+    children: Option<Range<usize>>,
+    /// Split count at which this node was created, 0 for the root. Nodes
+    /// are stored in creation order, so this never decreases along `nodes`.
+    created_at: u32,
+    /// Number of splits between the root and this node.
+    depth: u32,
+}
+
+/// Inclusive `(row, column)` corners of a rectangle of pixels.
+pub type Region = ((usize, usize), (usize, usize));
+
+fn union(a: Region, b: Region) -> Region {
+    (
+        (a.0.0.min(b.0.0), a.0.1.min(b.0.1)),
+        (a.1.0.max(b.1.0), a.1.1.max(b.1.1)),
+    )
+}
+
+/// A leaf of the current tree, as drawn by `QuadTree::render`.
+#[derive(Debug, Clone, Copy)]
+pub struct Leaf {
+    pub region: Region,
+    pub color: RGB<u8>,
+    /// Number of splits between the root and this leaf.
+    pub depth: u32,
+}
+
+impl Leaf {
+    /// `(rows, columns)` covered by the leaf.
+    pub fn size(&self) -> (u64, u64) {
+        let ((top, left), (bottom, right)) = self.region;
+        ((bottom - top + 1) as u64, (right - left + 1) as u64)
+    }
+}
+
+/// Result of asking the tree for one more split.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SplitOutcome {
+    Split,
+    /// Every leaf is a single pixel or blocked by the split options.
+    Converged,
+    /// The tree already reaches the target PSNR of the split options.
+    TargetReached,
 }
 
 impl Node {
@@ -23,29 +64,60 @@ impl Node {
             top_left,
             bottom_right,
             children: None,
+            created_at: 0,
+            depth: 0,
         }
     }
 
     fn height(&self) -> u64 {
-        (self.bottom_right.0 as u64) - (self.top_left.0 as u64)
+        (self.bottom_right.0 as u64) - (self.top_left.0 as u64) + 1
     }
 
     fn width(&self) -> u64 {
-        (self.bottom_right.1 as u64) - (self.top_left.1 as u64)
+        (self.bottom_right.1 as u64) - (self.top_left.1 as u64) + 1
     }
 
     fn can_split(&self) -> bool {
-        self.width() > 1 && self.height() > 1
+        self.width() > 1 || self.height() > 1
+    }
+
+    /// Whether splitting would keep every child at least `min_size` pixels
+    /// along each axis that gets divided.
+    fn fits_min_size(&self, min_size: usize) -> bool {
+        let min_size = min_size as u64;
+        (self.height() == 1 || self.height() / 2 >= min_size)
+            && (self.width() == 1 || self.width() / 2 >= min_size)
+    }
+
+    fn exceeds_max_size(&self, max_size: usize) -> bool {
+        let max_size = max_size as u64;
+        self.height() > max_size || self.width() > max_size
     }
 
-    fn split(&self) -> Option<(Node, Node, Node, Node)> {
-        if !self.can_split() {
+    /// Splits into four quadrants, or into two halves along the long axis
+    /// when the region is only one pixel thick. A node is only split once.
+    fn split(&self) -> Option<Vec<Node>> {
+        if self.children.is_some() || !self.can_split() {
             return None;
         }
 
         let split_h = (self.top_left.0 + self.bottom_right.0) / 2;
         let split_w = (self.top_left.1 + self.bottom_right.1) / 2;
 
+        if self.height() == 1 {
+            return Some(vec![
+                Node::leaf(self.top_left, (self.bottom_right.0, split_w)),
+                Node::leaf((self.top_left.0, split_w + 1), self.bottom_right),
+            ]);
+        }
+
+        if self.width() == 1 {
+            return Some(vec![
+                Node::leaf(self.top_left, (split_h, self.bottom_right.1)),
+                Node::leaf((split_h + 1, self.top_left.1), self.bottom_right),
+            ]);
+        }
+
         let top_left_node = Node::leaf(self.top_left, (split_h, split_w));
         let top_right_node = Node::leaf(
             (self.top_left.0, split_w + 1),
@@ -57,34 +129,70 @@ impl Node {
         );
         let bottom_right_node = Node::leaf((split_h + 1, split_w + 1), self.bottom_right);
 
-        Some((
+        Some(vec![
             top_left_node,
             top_right_node,
             bottom_left_node,
             bottom_right_node,
-        ))
+        ])
+    }
+}
+
+/// Settings that control which region gets split next.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SplitOptions {
+    /// Weight of the edge energy added to the variance of a region. Requires
+    /// the `ImageData` to be built with an edge map.
+    pub edge_weight: Option<f64>,
+    /// Leaves whose children would be smaller than this are never split.
+    pub min_leaf_size: Option<usize>,
+    /// Leaves larger than this are split before any other leaf.
+    pub max_leaf_size: Option<usize>,
+    /// Stop splitting once the whole image reaches this PSNR in dB.
+    pub target_psnr: Option<f64>,
+}
+
+impl SplitOptions {
+    fn allows_split(&self, node: &Node) -> bool {
+        node.can_split()
+            && self
+                .min_leaf_size
+                .is_none_or(|min_size| node.fits_min_size(min_size))
     }
 }
 
 struct OrdNode {
     node_idx: usize,
-    variance: u64,
+    oversized: bool,
+    priority: u64,
 }
 
 impl OrdNode {
-    pub fn new(nodes: &Vec<Node>, idx: usize, image_data: &ImageData) -> Self {
-        let top_left = nodes[idx].top_left;
-        let bottom_right = nodes[idx].bottom_right;
+    pub fn new(nodes: &[Node], idx: usize, image_data: &ImageData, options: &SplitOptions) -> Self {
+        let node = &nodes[idx];
+        let top_left = node.top_left;
+        let bottom_right = node.bottom_right;
+        let variance = image_data.variance(top_left, bottom_right);
+        let priority = match options.edge_weight {
+            Some(weight) => {
+                let edges = image_data.edge_energy(top_left, bottom_right) as f64;
+                variance.saturating_add((edges * weight) as u64)
+            }
+            None => variance,
+        };
         Self {
             node_idx: idx,
-            variance: image_data.variance(top_left, bottom_right),
+            oversized: options
+                .max_leaf_size
+                .is_some_and(|max_size| node.exceeds_max_size(max_size)),
+            priority,
         }
     }
 }
 
 impl PartialEq for OrdNode {
     fn eq(&self, other: &Self) -> bool {
-        self.variance == other.variance
+        self.cmp(other) == Ordering::Equal
     }
 }
 
@@ -92,98 +200,468 @@ impl Eq for OrdNode {}
 
 impl PartialOrd for OrdNode {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.variance.cmp(&other.variance))
+        Some(self.cmp(other))
     }
 }
 
 impl Ord for OrdNode {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.variance.cmp(&other.variance)
+        // Older nodes win ties, so the split order doesn't depend on the
+        // order the queue was filled in
+        (self.oversized, self.priority)
+            .cmp(&(other.oversized, other.priority))
+            .then_with(|| other.node_idx.cmp(&self.node_idx))
     }
 }
 
 pub struct QuadTree {
     image_data: ImageData,
+    options: SplitOptions,
     nodes: Vec<Node>,
     priority_queue: BinaryHeap<OrdNode>,
     dimensions: (usize, usize),
+    splits: u32,
+    /// Sum of `ImageData::error` over the current leaves.
+    total_error: u64,
 }
 
 const MAX_ALPHA: u8 = 100;
 
+const CHECKPOINT_MAGIC: &[u8; 4] = b"QTCK";
+const CHECKPOINT_VERSION: u32 = 2;
+
+fn write_u64(writer: &mut impl Write, value: u64) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_usize(reader: &mut impl Read) -> Result<usize, String> {
+    let value = read_u64(reader).map_err(|e| format!("Truncated checkpoint: {}", e))?;
+    usize::try_from(value).map_err(|_| format!("Checkpoint value {} is out of range", value))
+}
+
 impl QuadTree {
-    pub fn new(image_data: ImageData) -> Self {
+    pub fn new(image_data: ImageData, options: SplitOptions) -> Self {
         let dimensions = (image_data.height(), image_data.width());
         let root = Node::leaf((0, 0), (dimensions.0 - 1, dimensions.1 - 1));
         let nodes = vec![root];
         let mut priority_queue = BinaryHeap::new();
-        priority_queue.push(OrdNode::new(&nodes, 0, &image_data));
+        if options.allows_split(&nodes[0]) {
+            priority_queue.push(OrdNode::new(&nodes, 0, &image_data, &options));
+        }
 
+        let total_error = image_data.error(nodes[0].top_left, nodes[0].bottom_right);
         Self {
             image_data,
+            options,
             nodes,
             priority_queue,
             dimensions,
+            splits: 0,
+            total_error,
         }
     }
 
-    fn push_node(&mut self, node: Node) -> usize {
-        let new_node_idx = self.nodes.len();
-        self.nodes.push(node);
-        new_node_idx
+    /// Number of successful splits performed so far, including those
+    /// restored from a checkpoint.
+    pub fn splits(&self) -> u32 {
+        self.splits
     }
 
-    pub fn split_next(&mut self) -> Result<(), String> {
-        loop {
-            let Some(top) = self.priority_queue.pop() else {
-                return Err("No more nodes to split".to_string());
-            };
+    /// Number of nodes in the tree, split or not.
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Number of leaves. Every split turns exactly one leaf into an
+    /// internal node.
+    pub fn leaf_count(&self) -> usize {
+        self.nodes.len() - self.splits as usize
+    }
+
+    /// Sum of squared differences between the image and its current
+    /// render, over all pixels and channels. Kept up to date by
+    /// `split_next`, so it costs nothing to read.
+    pub fn total_error(&self) -> u64 {
+        self.total_error
+    }
+
+    /// Mean squared error of the current render over all channels.
+    pub fn mse(&self) -> f64 {
+        let (height, width) = self.dimensions;
+        self.total_error() as f64 / (height * width * 3) as f64
+    }
+
+    /// PSNR of the current render in dB, infinite once it is exact.
+    pub fn psnr(&self) -> f64 {
+        psnr(self.mse())
+    }
+
+    /// Sums of the image's channels and their squares, which a checkpoint
+    /// stores to recognise the image it was written for.
+    fn fingerprint(image_data: &ImageData) -> [u64; 6] {
+        let bottom_right = (image_data.height() - 1, image_data.width() - 1);
+        let sum = image_data.sum((0, 0), bottom_right);
+        let square_sum = image_data.square_sum((0, 0), bottom_right);
+        [
+            sum.r,
+            sum.g,
+            sum.b,
+            square_sum.r,
+            square_sum.g,
+            square_sum.b,
+        ]
+    }
+
+    /// Writes the nodes so the tree can be rebuilt with `restore` without
+    /// replaying any splits. The image itself is only fingerprinted; the
+    /// priority queue is rebuilt on restore.
+    pub fn snapshot(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(CHECKPOINT_MAGIC)?;
+        writer.write_all(&CHECKPOINT_VERSION.to_le_bytes())?;
+        write_u64(writer, self.dimensions.0 as u64)?;
+        write_u64(writer, self.dimensions.1 as u64)?;
+        for value in Self::fingerprint(&self.image_data) {
+            write_u64(writer, value)?;
+        }
+        write_u64(writer, self.splits as u64)?;
+
+        write_u64(writer, self.nodes.len() as u64)?;
+        for node in &self.nodes {
+            write_u64(writer, node.top_left.0 as u64)?;
+            write_u64(writer, node.top_left.1 as u64)?;
+            write_u64(writer, node.bottom_right.0 as u64)?;
+            write_u64(writer, node.bottom_right.1 as u64)?;
+            let children = node.children.clone().unwrap_or(0..0);
+            write_u64(writer, children.start as u64)?;
+            write_u64(writer, children.len() as u64)?;
+        }
 
-            if let Some((top_left, top_right, bottom_left, bottom_right)) =
-                self.nodes[top.node_idx].split()
+        Ok(())
+    }
+
+    /// Rebuilds a tree written by `snapshot` for the same image. Every leaf
+    /// that `options` allow to split is queued, so they may differ from the
+    /// options the checkpoint was written with.
+    pub fn restore(
+        image_data: ImageData,
+        options: SplitOptions,
+        reader: &mut impl Read,
+    ) -> Result<Self, String> {
+        let mut magic = [0; 4];
+        let mut version = [0; 4];
+        reader
+            .read_exact(&mut magic)
+            .and_then(|_| reader.read_exact(&mut version))
+            .map_err(|e| format!("Truncated checkpoint: {}", e))?;
+        if &magic != CHECKPOINT_MAGIC {
+            return Err("Not a quad tree checkpoint".to_string());
+        }
+        let version = u32::from_le_bytes(version);
+        if version != CHECKPOINT_VERSION {
+            return Err(format!("Unsupported checkpoint version {}", version));
+        }
+
+        let dimensions = (read_usize(reader)?, read_usize(reader)?);
+        if dimensions != (image_data.height(), image_data.width()) {
+            return Err(format!(
+                "Checkpoint is for a {}x{} image, but the input is {}x{}",
+                dimensions.1,
+                dimensions.0,
+                image_data.width(),
+                image_data.height()
+            ));
+        }
+        let mut fingerprint = [0; 6];
+        for value in &mut fingerprint {
+            *value = read_u64(reader).map_err(|e| format!("Truncated checkpoint: {}", e))?;
+        }
+        if fingerprint != Self::fingerprint(&image_data) {
+            return Err("Checkpoint was written for a different image".to_string());
+        }
+        let splits = u32::try_from(read_usize(reader)?)
+            .map_err(|_| "Checkpoint split count is out of range".to_string())?;
+
+        let node_count = read_usize(reader)?;
+        let mut nodes = Vec::with_capacity(node_count.min(1 << 20));
+        for _ in 0..node_count {
+            let top_left = (read_usize(reader)?, read_usize(reader)?);
+            let bottom_right = (read_usize(reader)?, read_usize(reader)?);
+            if top_left.0 > bottom_right.0
+                || top_left.1 > bottom_right.1
+                || bottom_right.0 >= dimensions.0
+                || bottom_right.1 >= dimensions.1
             {
-                let top_left_idx = self.push_node(top_left);
-                let top_right_idx = self.push_node(top_right);
-                let bottom_left_idx = self.push_node(bottom_left);
-                let bottom_right_idx = self.push_node(bottom_right);
-
-                let parent_node = &mut self.nodes[top.node_idx];
-                parent_node.children = Some(NodeChildren {
-                    top_left_idx,
-                    top_right_idx,
-                    bottom_left_idx,
-                    bottom_right_idx,
-                });
-
-                for child in [
-                    top_left_idx,
-                    top_right_idx,
-                    bottom_left_idx,
-                    bottom_right_idx,
-                ]
-                .into_iter()
-                {
-                    self.priority_queue
-                        .push(OrdNode::new(&self.nodes, child, &self.image_data));
+                return Err("Checkpoint contains a region outside the image".to_string());
+            }
+            let start = read_usize(reader)?;
+            let count = read_usize(reader)?;
+            let mut node = Node::leaf(top_left, bottom_right);
+            if count > 0 {
+                if start.saturating_add(count) > node_count {
+                    return Err("Checkpoint contains an invalid child index".to_string());
+                }
+                node.children = Some(start..start + count);
+            }
+            nodes.push(node);
+        }
+        if nodes.is_empty() {
+            return Err("Checkpoint contains no nodes".to_string());
+        }
+        // Every split turns exactly one leaf into a parent, which
+        // `leaf_count` relies on
+        let parents = nodes.iter().filter(|node| node.children.is_some()).count();
+        if parents != splits as usize {
+            return Err(format!(
+                "Checkpoint records {} splits but contains {} split nodes",
+                splits, parents
+            ));
+        }
+
+        let priority_queue = (0..nodes.len())
+            .filter(|&idx| nodes[idx].children.is_none() && options.allows_split(&nodes[idx]))
+            .map(|idx| OrdNode::new(&nodes, idx, &image_data, &options))
+            .collect();
+
+        // Children are stored in split order, so ranking the child ranges
+        // recovers when each node was created
+        let mut child_ranges: Vec<Range<usize>> = nodes
+            .iter()
+            .filter_map(|node| node.children.clone())
+            .collect();
+        child_ranges.sort_unstable_by_key(|range| range.start);
+        for (split, range) in child_ranges.into_iter().enumerate() {
+            for child in &mut nodes[range] {
+                child.created_at = split as u32 + 1;
+            }
+        }
+
+        // Children always follow their parent, so one pass sets every depth
+        for idx in 0..nodes.len() {
+            let depth = nodes[idx].depth + 1;
+            if let Some(children) = nodes[idx].children.clone() {
+                if children.start <= idx {
+                    return Err("Checkpoint contains an invalid child index".to_string());
                 }
+                for child in &mut nodes[children] {
+                    child.depth = depth;
+                }
+            }
+        }
+
+        let total_error = nodes
+            .iter()
+            .filter(|node| node.children.is_none())
+            .map(|node| image_data.error(node.top_left, node.bottom_right))
+            .sum();
+
+        Ok(Self {
+            image_data,
+            options,
+            nodes,
+            priority_queue,
+            dimensions,
+            splits,
+            total_error,
+        })
+    }
 
-                return Ok(());
+    pub fn split_next(&mut self) -> SplitOutcome {
+        if self
+            .options
+            .target_psnr
+            .is_some_and(|target| self.psnr() >= target)
+        {
+            return SplitOutcome::TargetReached;
+        }
+
+        while let Some(top) = self.priority_queue.pop() {
+            let Some(children) = self.nodes[top.node_idx].split() else {
+                continue;
+            };
+
+            let first_child_idx = self.nodes.len();
+            let depth = self.nodes[top.node_idx].depth + 1;
+            self.nodes.extend(children.into_iter().map(|mut child| {
+                child.created_at = self.splits + 1;
+                child.depth = depth;
+                child
+            }));
+            let child_indices = first_child_idx..self.nodes.len();
+            self.nodes[top.node_idx].children = Some(child_indices.clone());
+
+            // The parent's error is part of the total, so removing it first
+            // can't underflow
+            let parent = &self.nodes[top.node_idx];
+            self.total_error -= self.image_data.error(parent.top_left, parent.bottom_right);
+            for child in &self.nodes[child_indices.clone()] {
+                self.total_error += self.image_data.error(child.top_left, child.bottom_right);
+            }
+
+            for child in child_indices {
+                if !self.options.allows_split(&self.nodes[child]) {
+                    continue;
+                }
+                self.priority_queue.push(OrdNode::new(
+                    &self.nodes,
+                    child,
+                    &self.image_data,
+                    &self.options,
+                ));
             }
+
+            self.splits += 1;
+            return SplitOutcome::Split;
         }
+
+        SplitOutcome::Converged
+    }
+
+    /// Bounding box of the leaves replaced by splits `after + 1..=until`,
+    /// or `None` if no split happened in that range.
+    pub fn changed_region(&self, after: u32, until: u32) -> Option<Region> {
+        let start = self.nodes.partition_point(|node| node.created_at <= after);
+        let end = self.nodes.partition_point(|node| node.created_at <= until);
+        self.nodes[start..end]
+            .iter()
+            .map(|node| (node.top_left, node.bottom_right))
+            .reduce(union)
+    }
+
+    /// Whether the node had been split once the tree reached `splits`.
+    fn is_split_at(&self, node: &Node, splits: u32) -> bool {
+        node.children
+            .as_ref()
+            .is_some_and(|children| self.nodes[children.start].created_at <= splits)
     }
 
     pub fn render<T>(
         &self,
         color_to_pixel: fn(RGB<u64>) -> T,
-        outline: Option<RGB<u8>>,
+        options: &RenderOptions,
     ) -> ImageBuffer<T, Vec<u8>>
     where
         T: Pixel<Subpixel = u8>,
     {
+        self.render_region(color_to_pixel, options, self.full_region(), self.splits)
+    }
+
+    pub fn full_region(&self) -> Region {
         let (height, width) = self.dimensions;
-        let mut image = ImageBuffer::new(width as u32, height as u32);
+        ((0, 0), (height - 1, width - 1))
+    }
+
+    /// `(rows, columns)` of a full render with `options`.
+    pub fn output_dimensions(&self, options: &RenderOptions) -> (usize, usize) {
+        options
+            .scale
+            .map_or(self.dimensions, |scale| scale.dimensions(self.dimensions))
+    }
 
-        let outline_pixel = outline.map(|c| color_to_pixel(c.into()));
+    /// Pixels of a render with `options` that show the image pixels in
+    /// `region`. Never empty, even where the output is smaller.
+    pub fn output_region(&self, options: &RenderOptions, region: Region) -> Region {
+        let (height, width) = self.dimensions;
+        let (output_height, output_width) = self.output_dimensions(options);
+        let span = |start: usize, end: usize, from: usize, to: usize| {
+            let first = start * to / from;
+            let last = ((end + 1) * to / from).max(first + 1) - 1;
+            (first.min(to - 1), last.min(to - 1))
+        };
+        let (top, bottom) = span(region.0.0, region.1.0, height, output_height);
+        let (left, right) = span(region.0.1, region.1.1, width, output_width);
+        ((top, left), (bottom, right))
+    }
+
+    /// Output pixels covered by `node` in a render of `output_dimensions`,
+    /// or `None` if it is too small to cover any.
+    fn output_bounds(&self, output_dimensions: (usize, usize), node: &Node) -> Option<Region> {
+        let (height, width) = self.dimensions;
+        let (output_height, output_width) = output_dimensions;
+        let top = node.top_left.0 * output_height / height;
+        let left = node.top_left.1 * output_width / width;
+        let bottom = (node.bottom_right.0 + 1) * output_height / height;
+        let right = (node.bottom_right.1 + 1) * output_width / width;
+        (bottom > top && right > left).then(|| ((top, left), (bottom - 1, right - 1)))
+    }
+
+    /// Value shown at the high end of a heatmap's colour map; the low end
+    /// is always 0. Covers every node created so far, so every frame of an
+    /// animation uses the same scale.
+    pub fn color_range(&self, color_mode: ColorMode) -> f64 {
+        match color_mode {
+            ColorMode::Average => 0.0,
+            ColorMode::Depth => self.max_depth() as f64,
+            ColorMode::Variance => self
+                .nodes
+                .iter()
+                .map(|node| self.deviation(node))
+                .fold(0.0, f64::max),
+            ColorMode::SplitOrder => self.splits as f64,
+        }
+    }
+
+    /// Standard deviation of the node's pixels, summed over the channels.
+    fn deviation(&self, node: &Node) -> f64 {
+        let area = node.height() * node.width();
+        (self.image_data.variance(node.top_left, node.bottom_right) as f64 / area as f64).sqrt()
+    }
+
+    fn node_color(&self, node: &Node, color_mode: ColorMode, color_range: f64) -> RGB<u64> {
+        let value = match color_mode {
+            ColorMode::Average => {
+                return self.image_data.average(node.top_left, node.bottom_right);
+            }
+            ColorMode::Depth => node.depth as f64,
+            ColorMode::Variance => self.deviation(node),
+            ColorMode::SplitOrder => node.created_at as f64,
+        };
+        let t = if color_range > 0.0 {
+            value / color_range
+        } else {
+            0.0
+        };
+        color_map(t).into()
+    }
+
+    /// Depth of the deepest node created so far. Outline gradients span this
+    /// depth, so every frame of an animation shares the same colours.
+    pub fn max_depth(&self) -> u32 {
+        self.nodes.iter().map(|node| node.depth).max().unwrap_or(0)
+    }
+
+    /// Renders only the pixels inside `region`, as the tree looked after
+    /// `splits` splits. The returned image is the size of the region, with
+    /// its top left corner at the region's origin.
+    pub fn render_region<T>(
+        &self,
+        color_to_pixel: fn(RGB<u64>) -> T,
+        options: &RenderOptions,
+        region: Region,
+        splits: u32,
+    ) -> ImageBuffer<T, Vec<u8>>
+    where
+        T: Pixel<Subpixel = u8>,
+    {
+        let output_dimensions = self.output_dimensions(options);
+        let ((region_y, region_x), (region_end_y, region_end_x)) =
+            self.output_region(options, region);
+        let mut image = ImageBuffer::new(
+            (region_end_x - region_x + 1) as u32,
+            (region_end_y - region_y + 1) as u32,
+        );
+
+        let outline_colors = options
+            .outline
+            .map(|style| style.depth_colors(self.max_depth()))
+            .unwrap_or_default();
+        let background = options.background.into();
+        let color_range = self.color_range(options.color_mode);
 
         let mut queue = VecDeque::new();
         queue.push_back(0);
@@ -191,34 +669,72 @@ impl QuadTree {
         while let Some(current_node_idx) = queue.pop_front() {
             let node = &self.nodes[current_node_idx];
 
-            if let Some(children) = &node.children {
-                queue.push_back(children.top_left_idx);
-                queue.push_back(children.top_right_idx);
-                queue.push_back(children.bottom_left_idx);
-                queue.push_back(children.bottom_right_idx);
+            // Nodes that shrink to nothing at a smaller output size are
+            // covered by their neighbours
+            let Some(((start_y, start_x), (end_y, end_x))) =
+                self.output_bounds(output_dimensions, node)
+            else {
+                continue;
+            };
+
+            if start_y > region_end_y
+                || start_x > region_end_x
+                || end_y < region_y
+                || end_x < region_x
+            {
+                continue;
+            }
+
+            if self.is_split_at(node, splits) {
+                queue.extend(node.children.clone().unwrap_or_default());
             } else {
-                let (start_y, start_x) = node.top_left;
-                let (end_y, end_x) = node.bottom_right;
-                let color = self.image_data.average(node.top_left, node.bottom_right);
-                let pixel = color_to_pixel(color);
+                let color = self.node_color(node, options.color_mode, color_range);
+                let size = ((end_y - start_y + 1) as u64, (end_x - start_x + 1) as u64);
 
-                for x in start_x..=end_x {
-                    for y in start_y..=end_y {
-                        image.put_pixel(x as u32, y as u32, pixel);
-                    }
-                }
+                let outline = options
+                    .outline
+                    .filter(|style| style.applies_to(node.height(), node.width()))
+                    .map(|style| {
+                        let outline_color = outline_colors[node.depth as usize];
+                        (style.width, style.blend(color, outline_color))
+                    });
+
+                let rows = start_y.max(region_y)..=end_y.min(region_end_y);
+                let columns = start_x.max(region_x)..=end_x.min(region_end_x);
 
-                if let Some(outline_pixel) = outline_pixel {
-                    for y in [start_y, end_y].into_iter() {
-                        for x in start_x..=end_x {
-                            image.put_pixel(x as u32, y as u32, outline_pixel);
+                if options.shape != LeafShape::Rectangle {
+                    for y in rows {
+                        for x in columns.clone() {
+                            let offset = (y - start_y, x - start_x);
+                            let shaded = options
+                                .shape
+                                .shade(size, offset, color, outline, background);
+                            image.put_pixel(
+                                (x - region_x) as u32,
+                                (y - region_y) as u32,
+                                color_to_pixel(shaded),
+                            );
                         }
                     }
+                    continue;
+                }
 
-                    for x in [start_x, end_x].into_iter() {
-                        for y in start_y..=end_y {
-                            image.put_pixel(x as u32, y as u32, outline_pixel);
-                        }
+                let pixel = color_to_pixel(color);
+                let outline = outline.map(|(width, color)| (width, color_to_pixel(color)));
+                for y in rows {
+                    for x in columns.clone() {
+                        let pixel = match outline {
+                            Some((width, outline_pixel))
+                                if y - start_y < width
+                                    || end_y - y < width
+                                    || x - start_x < width
+                                    || end_x - x < width =>
+                            {
+                                outline_pixel
+                            }
+                            _ => pixel,
+                        };
+                        image.put_pixel((x - region_x) as u32, (y - region_y) as u32, pixel);
                     }
                 }
             }
@@ -227,17 +743,285 @@ impl QuadTree {
         image
     }
 
-    pub fn render_rgb(&self, outline: Option<RGB<u8>>) -> RgbImage {
+    pub fn render_rgb(&self, options: &RenderOptions) -> RgbImage {
         self.render(
             |color| Rgb([color.r as u8, color.g as u8, color.b as u8]),
-            outline,
+            options,
         )
     }
 
-    pub fn render_rgba(&self, outline: Option<RGB<u8>>) -> RgbaImage {
-        self.render(
+    pub fn render_rgba_region(
+        &self,
+        options: &RenderOptions,
+        region: Region,
+        splits: u32,
+    ) -> RgbaImage {
+        self.render_region(
             |color| Rgba([color.r as u8, color.g as u8, color.b as u8, MAX_ALPHA]),
-            outline,
+            options,
+            region,
+            splits,
         )
     }
+
+    /// Leaves of the current tree in breadth first order, coloured by
+    /// `color_mode`.
+    pub fn leaves(&self, color_mode: ColorMode) -> Vec<Leaf> {
+        let color_range = self.color_range(color_mode);
+        let mut leaves = Vec::new();
+        let mut queue = VecDeque::from([0]);
+        while let Some(idx) = queue.pop_front() {
+            let node = &self.nodes[idx];
+            match &node.children {
+                Some(children) => queue.extend(children.clone()),
+                None => {
+                    let color = self.node_color(node, color_mode, color_range);
+                    leaves.push(Leaf {
+                        region: (node.top_left, node.bottom_right),
+                        color: RGB::new(color.r as u8, color.g as u8, color.b as u8),
+                        depth: node.depth,
+                    });
+                }
+            }
+        }
+        leaves
+    }
+
+    /// Average colour of every node created so far, weighted by its area if
+    /// it is a leaf of the current tree and by 1 otherwise. Every colour any
+    /// earlier state of the tree shows is included.
+    pub fn weighted_colors(&self, color_mode: ColorMode) -> Vec<(RGB<u8>, u64)> {
+        let color_range = self.color_range(color_mode);
+        self.nodes
+            .iter()
+            .map(|node| {
+                let color = self.node_color(node, color_mode, color_range);
+                let color = RGB::new(color.r as u8, color.g as u8, color.b as u8);
+                let weight = if node.children.is_some() {
+                    1
+                } else {
+                    node.height() * node.width()
+                };
+                (color, weight)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics;
+
+    /// Offset of the split count in a checkpoint: magic, version, the two
+    /// dimensions and the six fingerprint sums come first.
+    const SPLITS_OFFSET: usize = 72;
+
+    fn pixels() -> Vec<Vec<RGB<u64>>> {
+        (0..12u64)
+            .map(|y| {
+                (0..16u64)
+                    .map(|x| RGB::new(x * 16, y * 20, (x ^ y) * 16))
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn strip_pixels(height: u64, width: u64) -> Vec<Vec<RGB<u64>>> {
+        (0..height)
+            .map(|y| {
+                (0..width)
+                    .map(|x| RGB::new(x * 19, y * 100, (x * 7 + y) % 5 * 50))
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn image_data() -> ImageData {
+        ImageData::new(&pixels(), false).unwrap()
+    }
+
+    fn strip_data(height: u64, width: u64) -> ImageData {
+        ImageData::new(&strip_pixels(height, width), false).unwrap()
+    }
+
+    fn rgb_image(pixels: &[Vec<RGB<u64>>]) -> RgbImage {
+        RgbImage::from_fn(pixels[0].len() as u32, pixels.len() as u32, |x, y| {
+            let pixel = pixels[y as usize][x as usize];
+            Rgb([pixel.r as u8, pixel.g as u8, pixel.b as u8])
+        })
+    }
+
+    fn split_tree(splits: u32) -> QuadTree {
+        let mut quad_tree = QuadTree::new(image_data(), SplitOptions::default());
+        for _ in 0..splits {
+            assert_eq!(quad_tree.split_next(), SplitOutcome::Split);
+        }
+        quad_tree
+    }
+
+    fn snapshot(quad_tree: &QuadTree) -> Vec<u8> {
+        let mut checkpoint = Vec::new();
+        quad_tree.snapshot(&mut checkpoint).unwrap();
+        checkpoint
+    }
+
+    fn restore(checkpoint: &[u8]) -> Result<QuadTree, String> {
+        QuadTree::restore(image_data(), SplitOptions::default(), &mut &checkpoint[..])
+    }
+
+    #[test]
+    fn restored_checkpoint_matches_the_tree() {
+        let mut quad_tree = split_tree(20);
+        let checkpoint = snapshot(&quad_tree);
+        let mut restored = restore(&checkpoint).unwrap();
+
+        assert_eq!(snapshot(&restored), checkpoint);
+        assert_eq!(restored.splits(), quad_tree.splits());
+        assert_eq!(restored.leaf_count(), quad_tree.leaf_count());
+        assert_eq!(restored.total_error(), quad_tree.total_error());
+
+        // Both carry on splitting the same leaves
+        for _ in 0..10 {
+            assert_eq!(restored.split_next(), quad_tree.split_next());
+        }
+        let options = RenderOptions::default();
+        assert_eq!(
+            restored.render_rgb(&options),
+            quad_tree.render_rgb(&options)
+        );
+        assert_eq!(restored.total_error(), quad_tree.total_error());
+    }
+
+    #[test]
+    fn tampered_split_count_is_rejected() {
+        let mut checkpoint = snapshot(&split_tree(20));
+        for splits in [19u64, 21, 1000] {
+            checkpoint[SPLITS_OFFSET..SPLITS_OFFSET + 8].copy_from_slice(&splits.to_le_bytes());
+            assert!(restore(&checkpoint).is_err());
+        }
+    }
+
+    #[test]
+    fn checkpoint_for_another_image_is_rejected() {
+        let checkpoint = snapshot(&split_tree(20));
+        let data: Vec<Vec<RGB<u64>>> = (0..12u64)
+            .map(|y| (0..16u64).map(|x| RGB::new(x * 16, y * 20, 0)).collect())
+            .collect();
+        let other = ImageData::new(&data, false).unwrap();
+        assert_eq!(
+            QuadTree::restore(other, SplitOptions::default(), &mut &checkpoint[..])
+                .err()
+                .as_deref(),
+            Some("Checkpoint was written for a different image")
+        );
+    }
+
+    #[test]
+    fn restore_queues_the_leaves_its_options_allow() {
+        let options = SplitOptions {
+            min_leaf_size: Some(4),
+            ..SplitOptions::default()
+        };
+        let mut quad_tree = QuadTree::new(image_data(), options);
+        while quad_tree.split_next() == SplitOutcome::Split {}
+        let checkpoint = snapshot(&quad_tree);
+
+        let mut same = QuadTree::restore(image_data(), options, &mut &checkpoint[..]).unwrap();
+        assert_eq!(same.split_next(), SplitOutcome::Converged);
+        // Leaves held back by the old minimum can split under the new one
+        let mut smaller = restore(&checkpoint).unwrap();
+        assert_eq!(smaller.split_next(), SplitOutcome::Split);
+    }
+
+    /// Splits a `height` x `width` image until it converges, checking that
+    /// every split after the first `quad_splits` adds two children.
+    fn split_strip(height: u64, width: u64, quad_splits: usize) -> QuadTree {
+        let mut quad_tree = QuadTree::new(strip_data(height, width), SplitOptions::default());
+        let mut splits = 0;
+        loop {
+            let nodes = quad_tree.node_count();
+            match quad_tree.split_next() {
+                SplitOutcome::Split => {
+                    let added = if splits < quad_splits { 4 } else { 2 };
+                    assert_eq!(quad_tree.node_count() - nodes, added);
+                    splits += 1;
+                }
+                outcome => {
+                    assert_eq!(outcome, SplitOutcome::Converged);
+                    break;
+                }
+            }
+        }
+        quad_tree
+    }
+
+    #[test]
+    fn strips_split_in_two_down_to_single_pixels() {
+        for (height, width, quad_splits) in [(1, 7, 0), (7, 1, 0), (2, 9, 1)] {
+            let mut quad_tree = split_strip(height, width, quad_splits);
+
+            let leaves = quad_tree.leaves(ColorMode::Average);
+            assert_eq!(leaves.len(), (height * width) as usize);
+            assert!(leaves.iter().all(|leaf| leaf.size() == (1, 1)));
+            assert_eq!(quad_tree.leaf_count(), leaves.len());
+            assert_eq!(quad_tree.total_error(), 0);
+
+            for _ in 0..3 {
+                assert_eq!(quad_tree.split_next(), SplitOutcome::Converged);
+            }
+            assert_eq!(quad_tree.leaf_count(), leaves.len());
+        }
+    }
+
+    #[test]
+    fn leaf_count_matches_the_leaves() {
+        for splits in [0, 1, 5, 20, 60] {
+            let quad_tree = split_tree(splits);
+            assert_eq!(
+                quad_tree.leaf_count(),
+                quad_tree.leaves(ColorMode::Average).len()
+            );
+        }
+    }
+
+    #[test]
+    fn mse_matches_the_render() {
+        for pixels in [pixels(), strip_pixels(1, 13), strip_pixels(2, 9)] {
+            let original = rgb_image(&pixels);
+            let image_data = ImageData::new(&pixels, false).unwrap();
+            let mut quad_tree = QuadTree::new(image_data, SplitOptions::default());
+            for splits in [0, 1, 3, 10, 40] {
+                while quad_tree.splits() < splits && quad_tree.split_next() == SplitOutcome::Split {
+                }
+                let render = quad_tree.render_rgb(&RenderOptions::default());
+                let mse = metrics::channel_mse(&original, &render);
+                let expected = mse.iter().sum::<f64>() / 3.0;
+                assert!((quad_tree.mse() - expected).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn target_psnr_stops_at_the_first_split_that_reaches_it() {
+        let options = SplitOptions {
+            target_psnr: Some(30.0),
+            ..SplitOptions::default()
+        };
+        let mut quad_tree = QuadTree::new(image_data(), options);
+        let mut psnr = quad_tree.psnr();
+        let outcome = loop {
+            match quad_tree.split_next() {
+                SplitOutcome::Split => {
+                    assert!(psnr < 30.0);
+                    psnr = quad_tree.psnr();
+                }
+                outcome => break outcome,
+            }
+        };
+
+        assert_eq!(outcome, SplitOutcome::TargetReached);
+        assert!(quad_tree.psnr() >= 30.0);
+        assert_eq!(quad_tree.split_next(), SplitOutcome::TargetReached);
+    }
 }
@@ -0,0 +1,297 @@
+use crate::image_processor::RGB;
+use image::{GenericImage, Rgb, RgbImage};
+
+/// How leaf outlines are drawn.
+#[derive(Debug, Clone, Copy)]
+pub struct OutlineStyle {
+    /// Outline colour of the root, and of every depth without a gradient.
+    pub color: RGB<u8>,
+    /// Outline colour of the deepest leaves. Depths in between are
+    /// interpolated from `color`.
+    pub gradient_end: Option<RGB<u8>>,
+    /// Thickness in pixels, drawn inside each leaf.
+    pub width: usize,
+    /// Opacity of the outline over the leaf colour, from 0 to 1.
+    pub alpha: f64,
+    /// Leaves smaller than this on either side are drawn without an outline.
+    pub min_leaf_size: Option<usize>,
+}
+
+impl OutlineStyle {
+    pub fn new(color: RGB<u8>) -> Self {
+        Self {
+            color,
+            gradient_end: None,
+            width: 1,
+            alpha: 1.0,
+            min_leaf_size: None,
+        }
+    }
+
+    /// Outline colour for each depth from 0 to `max_depth`.
+    pub fn depth_colors(&self, max_depth: u32) -> Vec<RGB<u8>> {
+        (0..=max_depth)
+            .map(|depth| match self.gradient_end {
+                Some(end) if max_depth > 0 => {
+                    lerp(self.color, end, depth as f64 / max_depth as f64)
+                }
+                _ => self.color,
+            })
+            .collect()
+    }
+
+    pub fn applies_to(&self, height: u64, width: u64) -> bool {
+        self.min_leaf_size
+            .is_none_or(|min_size| height.min(width) >= min_size as u64)
+    }
+
+    /// Mixes `outline` over the leaf colour `fill`.
+    pub fn blend(&self, fill: RGB<u64>, outline: RGB<u8>) -> RGB<u64> {
+        if self.alpha >= 1.0 {
+            return outline.into();
+        }
+        let mix = |fill: u64, outline: u8| {
+            (fill as f64 * (1.0 - self.alpha) + outline as f64 * self.alpha).round() as u64
+        };
+        RGB::new(
+            mix(fill.r, outline.r),
+            mix(fill.g, outline.g),
+            mix(fill.b, outline.b),
+        )
+    }
+
+    /// Colours the outlines add to a render with `max_depth`, for building
+    /// a palette. Blended outlines depend on the leaf below, so they add
+    /// none that can be listed up front; the palette takes those from the
+    /// rendered image instead.
+    pub fn palette_colors(&self, max_depth: u32) -> Vec<RGB<u8>> {
+        if self.alpha < 1.0 {
+            return Vec::new();
+        }
+        let mut colors = self.depth_colors(max_depth);
+        colors.dedup();
+        colors
+    }
+}
+
+fn lerp(start: RGB<u8>, end: RGB<u8>, t: f64) -> RGB<u8> {
+    let mix = |a: u8, b: u8| (a as f64 + (b as f64 - a as f64) * t).round() as u8;
+    RGB::new(
+        mix(start.r, end.r),
+        mix(start.g, end.g),
+        mix(start.b, end.b),
+    )
+}
+
+/// Share of the short side of a leaf used as the corner radius of
+/// `LeafShape::RoundedRectangle`.
+const CORNER_RADIUS_FRACTION: f64 = 0.25;
+
+/// Shape drawn for each leaf. Anything but a rectangle leaves part of the
+/// leaf showing the background.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LeafShape {
+    #[default]
+    Rectangle,
+    /// The largest circle centred in the leaf.
+    Circle,
+    /// The ellipse touching all four sides of the leaf.
+    Ellipse,
+    RoundedRectangle,
+}
+
+impl LeafShape {
+    /// Corner radius of a `RoundedRectangle` leaf of `size` pixels.
+    pub fn corner_radius(size: (u64, u64)) -> f64 {
+        size.0.min(size.1) as f64 * CORNER_RADIUS_FRACTION
+    }
+
+    /// Signed distance in pixels from the centre of the pixel at `offset`
+    /// to the edge of the shape, negative inside. `size` and `offset` are
+    /// `(rows, columns)` relative to the leaf's top left corner.
+    fn signed_distance(self, size: (u64, u64), offset: (usize, usize)) -> f64 {
+        let half_h = size.0 as f64 / 2.0;
+        let half_w = size.1 as f64 / 2.0;
+        let dy = offset.0 as f64 + 0.5 - half_h;
+        let dx = offset.1 as f64 + 0.5 - half_w;
+
+        match self {
+            LeafShape::Rectangle => (dy.abs() - half_h).max(dx.abs() - half_w),
+            LeafShape::Circle => dy.hypot(dx) - half_h.min(half_w),
+            LeafShape::Ellipse => {
+                // First order approximation: the implicit function divided
+                // by its gradient
+                let k = (dy / half_h).hypot(dx / half_w);
+                if k == 0.0 {
+                    return -half_h.min(half_w);
+                }
+                let gradient = (dy / (half_h * half_h)).hypot(dx / (half_w * half_w)) / k;
+                (k - 1.0) / gradient
+            }
+            LeafShape::RoundedRectangle => {
+                let radius = Self::corner_radius(size);
+                let qy = dy.abs() - (half_h - radius);
+                let qx = dx.abs() - (half_w - radius);
+                qy.max(0.0).hypot(qx.max(0.0)) + qy.max(qx).min(0.0) - radius
+            }
+        }
+    }
+
+    /// Colour of the pixel at `offset` in a leaf of `size` filled with
+    /// `fill`, with edges anti-aliased against `background`. `outline` is
+    /// the thickness and colour of a band just inside the edge.
+    pub fn shade(
+        self,
+        size: (u64, u64),
+        offset: (usize, usize),
+        fill: RGB<u64>,
+        outline: Option<(usize, RGB<u64>)>,
+        background: RGB<u64>,
+    ) -> RGB<u64> {
+        let distance = self.signed_distance(size, offset);
+        let shape = coverage(distance);
+        let (inner, band_color) = match outline {
+            Some((width, color)) => (coverage(distance + width as f64), color),
+            None => (shape, fill),
+        };
+
+        let mix = |background: u64, band: u64, fill: u64| {
+            (background as f64 * (1.0 - shape)
+                + band as f64 * (shape - inner)
+                + fill as f64 * inner)
+                .round() as u64
+        };
+        RGB::new(
+            mix(background.r, band_color.r, fill.r),
+            mix(background.g, band_color.g, fill.g),
+            mix(background.b, band_color.b, fill.b),
+        )
+    }
+}
+
+/// Fraction of a pixel covered by a shape whose edge is `distance` pixels
+/// from the pixel's centre.
+fn coverage(distance: f64) -> f64 {
+    (0.5 - distance).clamp(0.0, 1.0)
+}
+
+/// Size of the output relative to the source image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputScale {
+    /// Both sides multiplied by the same factor.
+    Factor(f64),
+    /// An exact size, stretching the image if the aspect ratio differs.
+    Size { width: u32, height: u32 },
+}
+
+impl OutputScale {
+    /// `(rows, columns)` of the output for a source of `dimensions`.
+    pub fn dimensions(&self, dimensions: (usize, usize)) -> (usize, usize) {
+        match *self {
+            OutputScale::Factor(factor) => {
+                let scale = |side: usize| ((side as f64 * factor).round() as usize).max(1);
+                (scale(dimensions.0), scale(dimensions.1))
+            }
+            OutputScale::Size { width, height } => (height as usize, width as usize),
+        }
+    }
+}
+
+/// What decides the colour of a leaf.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ColorMode {
+    /// The average colour of the pixels the leaf covers.
+    #[default]
+    Average,
+    /// Depth in the tree, from the root to the deepest node.
+    Depth,
+    /// Standard deviation of the pixels the leaf covers, relative to the
+    /// largest of any node.
+    Variance,
+    /// Iteration at which the leaf was created, from the root to the last.
+    SplitOrder,
+}
+
+impl ColorMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ColorMode::Average => "average color",
+            ColorMode::Depth => "depth",
+            ColorMode::Variance => "standard deviation",
+            ColorMode::SplitOrder => "split order",
+        }
+    }
+}
+
+/// Stops of the colour map used by heatmaps, from low to high.
+pub const COLOR_MAP: [RGB<u8>; 5] = [
+    RGB { r: 68, g: 1, b: 84 },
+    RGB {
+        r: 59,
+        g: 82,
+        b: 139,
+    },
+    RGB {
+        r: 33,
+        g: 145,
+        b: 140,
+    },
+    RGB {
+        r: 94,
+        g: 201,
+        b: 98,
+    },
+    RGB {
+        r: 253,
+        g: 231,
+        b: 37,
+    },
+];
+
+/// Heatmap colour of `t`, clamped to 0..=1.
+pub fn color_map(t: f64) -> RGB<u8> {
+    let position = t.clamp(0.0, 1.0) * (COLOR_MAP.len() - 1) as f64;
+    let stop = (position as usize).min(COLOR_MAP.len() - 2);
+    lerp(COLOR_MAP[stop], COLOR_MAP[stop + 1], position - stop as f64)
+}
+
+/// Height of the legend strip added below an image `height` pixels tall.
+pub fn legend_height(height: u32) -> u32 {
+    (height / 25).max(8)
+}
+
+/// Returns `image` with a strip below it showing the colour map from low
+/// on the left to high on the right.
+pub fn with_legend(image: &RgbImage) -> RgbImage {
+    let (width, height) = image.dimensions();
+    let legend_height = legend_height(height);
+    let mut output = RgbImage::new(width, height + legend_height);
+    output
+        .copy_from(image, 0, 0)
+        .expect("image fits above the legend");
+    for x in 0..width {
+        let t = if width > 1 {
+            x as f64 / (width - 1) as f64
+        } else {
+            0.0
+        };
+        let color = color_map(t);
+        for y in height..height + legend_height {
+            output.put_pixel(x, y, Rgb([color.r, color.g, color.b]));
+        }
+    }
+    output
+}
+
+/// Everything about how a tree is drawn, independent of the split logic.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RenderOptions {
+    pub outline: Option<OutlineStyle>,
+    pub shape: LeafShape,
+    /// Colour around leaves that don't fill their rectangle.
+    pub background: RGB<u8>,
+    /// Output size, the source size if `None`. Outlines keep their width
+    /// in output pixels.
+    pub scale: Option<OutputScale>,
+    pub color_mode: ColorMode,
+}
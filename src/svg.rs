@@ -0,0 +1,288 @@
+use crate::image_processor::RGB;
+use crate::quad_tree::{Leaf, QuadTree, Region};
+use crate::render::{
+    COLOR_MAP, ColorMode, LeafShape, OutlineStyle, OutputScale, RenderOptions, legend_height,
+};
+use std::io::{self, Write};
+
+/// Writes the current leaves of `quad_tree` as an SVG document with a
+/// `viewBox` matching the image, one element per leaf. The output scale
+/// only sets the document's `width` and `height`. Without outlines,
+/// adjacent rectangles of the same colour are merged first.
+pub fn write_svg(
+    quad_tree: &QuadTree,
+    options: &RenderOptions,
+    writer: &mut impl Write,
+) -> io::Result<()> {
+    let ((_, _), (bottom, right)) = quad_tree.full_region();
+    let (height, width) = (bottom + 1, right + 1);
+
+    let (output_height, output_width) = quad_tree.output_dimensions(options);
+
+    // Heatmaps get their legend below the image, scaled along with it
+    let legend = if options.color_mode == ColorMode::Average {
+        0
+    } else {
+        legend_height(height as u32) as usize
+    };
+
+    write!(
+        writer,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" viewBox="0 0 {} {}""#,
+        output_width,
+        output_height + legend * output_height / height,
+        width,
+        height + legend
+    )?;
+    // An exact output size may stretch the image like a raster render does
+    if let Some(OutputScale::Size { .. }) = options.scale {
+        write!(writer, r#" preserveAspectRatio="none""#)?;
+    }
+    // Anti-aliasing would show seams between rectangles that share an edge
+    if options.shape == LeafShape::Rectangle {
+        write!(writer, r#" shape-rendering="crispEdges""#)?;
+    }
+    writeln!(writer, ">")?;
+
+    if options.shape != LeafShape::Rectangle {
+        writeln!(
+            writer,
+            r#"<rect width="{}" height="{}" fill="{}"/>"#,
+            width,
+            height,
+            hex(options.background)
+        )?;
+    }
+
+    let leaves = quad_tree.leaves(options.color_mode);
+    match options.outline {
+        Some(style) => {
+            let outline_colors = style.depth_colors(quad_tree.max_depth());
+            for leaf in &leaves {
+                let (height, width) = leaf.size();
+                let outline = style
+                    .applies_to(height, width)
+                    .then(|| (style, outline_colors[leaf.depth as usize]));
+                write_leaf(writer, options.shape, leaf, outline)?;
+            }
+        }
+        None if options.shape == LeafShape::Rectangle => {
+            for (region, color) in merge_runs(&leaves) {
+                let ((top, left), (bottom, right)) = region;
+                write_rect(writer, left, top, right - left + 1, bottom - top + 1, color)?;
+            }
+        }
+        None => {
+            for leaf in &leaves {
+                write_leaf(writer, options.shape, leaf, None)?;
+            }
+        }
+    }
+
+    if legend > 0 {
+        writeln!(writer, r#"<linearGradient id="legend">"#)?;
+        for (i, &color) in COLOR_MAP.iter().enumerate() {
+            writeln!(
+                writer,
+                r#"<stop offset="{}" stop-color="{}"/>"#,
+                i as f64 / (COLOR_MAP.len() - 1) as f64,
+                hex(color)
+            )?;
+        }
+        writeln!(writer, "</linearGradient>")?;
+        writeln!(
+            writer,
+            r#"<rect y="{}" width="{}" height="{}" fill="url(#legend)"/>"#,
+            height, width, legend
+        )?;
+    }
+
+    writeln!(writer, "</svg>")
+}
+
+fn write_rect(
+    writer: &mut impl Write,
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+    color: RGB<u8>,
+) -> io::Result<()> {
+    write!(writer, "<rect")?;
+    if x > 0 {
+        write!(writer, r#" x="{}""#, x)?;
+    }
+    if y > 0 {
+        write!(writer, r#" y="{}""#, y)?;
+    }
+    writeln!(
+        writer,
+        r#" width="{}" height="{}" fill="{}"/>"#,
+        width,
+        height,
+        hex(color)
+    )
+}
+
+/// Draws one leaf, with its outline as a stroke just inside the shape's
+/// edge. Leaves too small to show any fill inside the outline are filled
+/// with the outline colour instead.
+fn write_leaf(
+    writer: &mut impl Write,
+    shape: LeafShape,
+    leaf: &Leaf,
+    outline: Option<(OutlineStyle, RGB<u8>)>,
+) -> io::Result<()> {
+    let Some((style, outline_color)) = outline else {
+        return writeln!(
+            writer,
+            r#"<{} fill="{}"/>"#,
+            geometry(shape, leaf, 0.0),
+            hex(leaf.color)
+        );
+    };
+
+    let (height, width) = leaf.size();
+    let stroke = style.width as f64;
+    if height.min(width) as f64 <= 2.0 * stroke {
+        let fill = style.blend(leaf.color.into(), outline_color);
+        let fill = RGB::new(fill.r as u8, fill.g as u8, fill.b as u8);
+        return writeln!(
+            writer,
+            r#"<{} fill="{}"/>"#,
+            geometry(shape, leaf, 0.0),
+            hex(fill)
+        );
+    }
+
+    // A translucent stroke straddles the inset edge, so the fill has to
+    // cover the whole leaf underneath it
+    let inset = geometry(shape, leaf, stroke / 2.0);
+    if style.alpha < 1.0 {
+        writeln!(
+            writer,
+            r#"<{} fill="{}"/>"#,
+            geometry(shape, leaf, 0.0),
+            hex(leaf.color)
+        )?;
+        writeln!(
+            writer,
+            r#"<{} fill="none" stroke="{}" stroke-width="{}" stroke-opacity="{}"/>"#,
+            inset,
+            hex(outline_color),
+            stroke,
+            style.alpha
+        )
+    } else {
+        writeln!(
+            writer,
+            r#"<{} fill="{}" stroke="{}" stroke-width="{}"/>"#,
+            inset,
+            hex(leaf.color),
+            hex(outline_color),
+            stroke
+        )
+    }
+}
+
+/// Element name and position attributes of `leaf` drawn as `shape`, with
+/// every edge moved `inset` pixels towards the centre.
+fn geometry(shape: LeafShape, leaf: &Leaf, inset: f64) -> String {
+    let ((top, left), _) = leaf.region;
+    let (height, width) = leaf.size();
+    let (height, width) = (height as f64, width as f64);
+    let cx = left as f64 + width / 2.0;
+    let cy = top as f64 + height / 2.0;
+
+    match shape {
+        LeafShape::Rectangle => format!(
+            r#"rect x="{}" y="{}" width="{}" height="{}""#,
+            left as f64 + inset,
+            top as f64 + inset,
+            width - 2.0 * inset,
+            height - 2.0 * inset
+        ),
+        LeafShape::Circle => format!(
+            r#"circle cx="{}" cy="{}" r="{}""#,
+            cx,
+            cy,
+            height.min(width) / 2.0 - inset
+        ),
+        LeafShape::Ellipse => format!(
+            r#"ellipse cx="{}" cy="{}" rx="{}" ry="{}""#,
+            cx,
+            cy,
+            width / 2.0 - inset,
+            height / 2.0 - inset
+        ),
+        LeafShape::RoundedRectangle => format!(
+            r#"rect x="{}" y="{}" width="{}" height="{}" rx="{}""#,
+            left as f64 + inset,
+            top as f64 + inset,
+            width - 2.0 * inset,
+            height - 2.0 * inset,
+            (LeafShape::corner_radius(leaf.size()) - inset).max(0.0)
+        ),
+    }
+}
+
+/// Merges leaves of the same colour into larger rectangles: first runs
+/// along rows that share the same top and bottom edge, then runs of the
+/// result along columns that share the same left and right edge.
+fn merge_runs(leaves: &[Leaf]) -> Vec<(Region, RGB<u8>)> {
+    let key = |color: RGB<u8>| (color.r, color.g, color.b);
+
+    let mut rects: Vec<(Region, RGB<u8>)> = leaves
+        .iter()
+        .map(|leaf| (leaf.region, leaf.color))
+        .collect();
+
+    // Horizontal runs
+    rects.sort_unstable_by_key(|&(((top, left), (bottom, _)), color)| {
+        (top, bottom, key(color), left)
+    });
+    let mut merged: Vec<(Region, RGB<u8>)> = Vec::with_capacity(rects.len());
+    for (region, color) in rects {
+        match merged.last_mut() {
+            Some((last, last_color))
+                if *last_color == color
+                    && last.0.0 == region.0.0
+                    && last.1.0 == region.1.0
+                    && last.1.1 + 1 == region.0.1 =>
+            {
+                last.1.1 = region.1.1;
+            }
+            _ => merged.push((region, color)),
+        }
+    }
+
+    // Vertical runs
+    merged
+        .sort_unstable_by_key(|&(((top, left), (_, right)), color)| (left, right, key(color), top));
+    let mut rects: Vec<(Region, RGB<u8>)> = Vec::with_capacity(merged.len());
+    for (region, color) in merged {
+        match rects.last_mut() {
+            Some((last, last_color))
+                if *last_color == color
+                    && last.0.1 == region.0.1
+                    && last.1.1 == region.1.1
+                    && last.1.0 + 1 == region.0.0 =>
+            {
+                last.1.0 = region.1.0;
+            }
+            _ => rects.push((region, color)),
+        }
+    }
+
+    rects
+}
+
+/// `#rrggbb`, or `#rgb` when each channel repeats its digit.
+fn hex(color: RGB<u8>) -> String {
+    let RGB { r, g, b } = color;
+    if [r, g, b].iter().all(|c| c >> 4 == c & 0xf) {
+        format!("#{:x}{:x}{:x}", r & 0xf, g & 0xf, b & 0xf)
+    } else {
+        format!("#{:02x}{:02x}{:02x}", r, g, b)
+    }
+}
@@ -1,8 +1,17 @@
+use crate::animation::{AnimationFormat, AnimationWriter, FrameTiming, GlobalPalette};
+use crate::atomic_file::{self, AtomicFile};
 use crate::image_processor::{ImageData, RGB};
-use crate::quad_tree::QuadTree;
-use gif::{Encoder as GifEncoder, Frame, Repeat};
+use crate::metadata::{self, Metadata};
+use crate::metrics::{Metrics, report_file, tree_bits};
+use crate::output::{self, Event, OutputMode, status};
+use crate::quad_tree::{QuadTree, SplitOptions, SplitOutcome};
+use crate::render::{ColorMode, RenderOptions, with_legend};
+use crate::svg::write_svg;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::{CompressionType, FilterType, PngEncoder};
+use image::{ImageEncoder, ImageError, ImageFormat, ImageResult, RgbImage};
 use std::fs::File;
-use std::io::{self, BufWriter, Write};
+use std::io::{self, BufReader, BufWriter, Cursor, Read, Write};
 use std::path::Path;
 
 pub fn hex_to_rgb(hex: &str) -> Result<RGB<u8>, String> {
@@ -19,53 +28,195 @@ pub fn hex_to_rgb(hex: &str) -> Result<RGB<u8>, String> {
     Ok(RGB::new(r, g, b))
 }
 
+/// Shows progress as a bar redrawn in place on a terminal, as a line every
+/// tenth of the way when stdout is redirected, or as JSON events.
 pub fn print_progress(current: usize, total: usize, step_name: &str) {
+    // Whether `current` is the first step past a new 1/`parts` of `total`
+    let crosses =
+        |parts: usize| current == total || current * parts / total != (current - 1) * parts / total;
     let percentage = (current as f32 / total as f32 * 100.0) as usize;
-    let bar_width = 30;
-    let filled = (current as f32 / total as f32 * bar_width as f32) as usize;
-    let empty = bar_width - filled;
-
-    print!(
-        "\r{}: [{}{}] {}% ({}/{})",
-        step_name,
-        "█".repeat(filled),
-        "░".repeat(empty),
-        percentage,
-        current,
-        total
-    );
-    io::stdout().flush().unwrap();
 
-    if current == total {
-        println!(); // New line when complete
+    match output::mode() {
+        OutputMode::Quiet => {}
+        OutputMode::Json => {
+            if crosses(100) {
+                Event::new("progress")
+                    .string("step", step_name)
+                    .integer("current", current as u64)
+                    .integer("total", total as u64)
+                    .emit();
+            }
+        }
+        OutputMode::Normal | OutputMode::Verbose if output::interactive() => {
+            let bar_width = 30;
+            let filled = (current as f32 / total as f32 * bar_width as f32) as usize;
+            let empty = bar_width - filled;
+
+            output::print(format_args!(
+                "\r{}: [{}{}] {}% ({}/{})",
+                step_name,
+                "█".repeat(filled),
+                "░".repeat(empty),
+                percentage,
+                current,
+                total
+            ));
+
+            if current == total {
+                status!(); // New line when complete
+            }
+        }
+        OutputMode::Normal | OutputMode::Verbose => {
+            if crosses(10) {
+                status!("{}: {}% ({}/{})", step_name, percentage, current, total);
+            }
+        }
     }
 }
 
 pub fn print_step(message: &str) {
-    print!("{}...", message);
-    io::stdout().flush().unwrap();
+    if output::text() {
+        output::print(format_args!("{}...", message));
+    }
 }
 
 pub fn print_success() {
-    println!(" ✓ Complete");
+    status!(" ✓ Complete");
 }
 
 pub fn print_failure() {
-    println!(" ✗ Failed");
+    status!(" ✗ Failed");
+}
+
+/// Explains why the split loop ended after `iterations` iterations.
+fn print_stopped(outcome: SplitOutcome, quad_tree: &QuadTree, iterations: u32) {
+    let reason = match outcome {
+        SplitOutcome::TargetReached => "target_reached",
+        _ => "converged",
+    };
+    Event::new("stopped")
+        .string("reason", reason)
+        .integer("iterations", iterations as u64)
+        .emit();
+
+    // Only a bar redrawn in place leaves the line unfinished
+    if output::interactive() {
+        status!();
+    }
+    match outcome {
+        SplitOutcome::TargetReached => status!(
+            "Reached {:.2} dB PSNR after {} iterations",
+            quad_tree.psnr(),
+            iterations
+        ),
+        _ => status!(
+            "Converged after {} iterations, no regions left to split",
+            iterations
+        ),
+    }
+}
+
+/// Splits `quad_tree` until it has `iterations` splits, showing progress
+/// and calling `on_split` after each one. Returns `SplitOutcome::Split`
+/// once all of them are done, or the reason the tree stopped early, which
+/// has been printed.
+pub fn split_until(
+    quad_tree: &mut QuadTree,
+    iterations: u32,
+    mut on_split: impl FnMut(&QuadTree) -> Result<(), Box<dyn std::error::Error>>,
+) -> Result<SplitOutcome, Box<dyn std::error::Error>> {
+    for i in quad_tree.splits() + 1..=iterations {
+        print_progress(i as usize, iterations as usize, "Processing");
+
+        let outcome = quad_tree.split_next();
+        if outcome != SplitOutcome::Split {
+            print_stopped(outcome, quad_tree, i - 1);
+            return Ok(outcome);
+        }
+        on_split(quad_tree)?;
+    }
+    Ok(SplitOutcome::Split)
+}
+
+/// Refuses to write `output_file` over the input, or over any existing
+/// file unless `force` is set.
+pub fn check_overwrite(output_file: &str, input_file: &str, force: bool) -> Result<(), String> {
+    let output_path = Path::new(output_file);
+    if output_file == STDIO || !output_path.exists() {
+        return Ok(());
+    }
+    let same_file = match (
+        output_path.canonicalize(),
+        Path::new(input_file).canonicalize(),
+    ) {
+        (Ok(output), Ok(input)) => output == input,
+        _ => false,
+    };
+    if same_file {
+        Err(format!("Output file '{}' is the input file", output_file))
+    } else if !force {
+        Err(format!(
+            "Output file '{}' already exists, use --force to overwrite it",
+            output_file
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Stands for stdin as the input file and stdout as the output file.
+pub const STDIO: &str = "-";
+
+/// Encoding of a still image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StillFormat {
+    Raster(ImageFormat),
+    Svg,
+}
+
+impl StillFormat {
+    /// The format implied by the extension of `path`.
+    pub fn from_path(path: &str) -> Result<Self, String> {
+        let extension = Path::new(path).extension().and_then(|ext| ext.to_str());
+        match extension {
+            Some(ext) if is_svg(ext) => Ok(StillFormat::Svg),
+            _ => ImageFormat::from_path(path)
+                .map(StillFormat::Raster)
+                .map_err(|_| format!("Unsupported image format for '{}'", path)),
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            StillFormat::Raster(format) => format.extensions_str()[0],
+            StillFormat::Svg => "svg",
+        }
+    }
+}
+
+/// How still images are encoded.
+#[derive(Debug, Clone, Default)]
+pub struct EncodeOptions {
+    /// Format of every output regardless of its extension, taken from the
+    /// extension if `None`. Required for stdout.
+    pub format: Option<StillFormat>,
+    /// JPEG quality from 1 to 100, the encoder default if `None`.
+    pub jpeg_quality: Option<u8>,
+    pub png_compression: CompressionType,
+    /// Carried over from the input: the ICC profile and EXIF into PNG and
+    /// JPEG outputs, the timestamp onto every output file.
+    pub metadata: Metadata,
 }
 
 pub fn ensure_valid_output_file(
     output_file: &str,
     input_file: &str,
-    gif: bool,
+    animated: bool,
 ) -> Result<String, String> {
     let output_path = Path::new(output_file);
-    let input_path = Path::new(input_file);
-
-    let input_extension = input_path
+    let input_extension = Path::new(input_file)
         .extension()
-        .and_then(|ext| ext.to_str())
-        .ok_or_else(|| format!("Input file '{}' has no valid extension", input_file))?;
+        .and_then(|ext| ext.to_str());
 
     let output_stem = output_path
         .file_stem()
@@ -74,10 +225,38 @@ pub fn ensure_valid_output_file(
 
     let parent_dir = output_path.parent().unwrap_or_else(|| Path::new("."));
 
-    let target_extension = if gif {
-        "gif"
+    // Animations keep any supported animation extension and default to GIF,
+    // still images keep any extension they can be written as and otherwise
+    // match the input
+    let output_extension = output_path.extension().and_then(|ext| ext.to_str());
+    let target_extension = if animated {
+        match output_extension {
+            Some(ext) if AnimationFormat::from_extension(ext).is_some() => ext,
+            _ => "gif",
+        }
     } else {
-        &input_extension.to_lowercase()
+        match (output_extension, input_extension) {
+            (Some(ext), _) if is_svg(ext) => ext,
+            (Some(ext), _)
+                if ImageFormat::from_extension(ext)
+                    .is_some_and(|format| format.writing_enabled()) =>
+            {
+                ext
+            }
+            (_, Some(input_extension)) => &input_extension.to_lowercase(),
+            (_, None) if input_file == STDIO => {
+                return Err(format!(
+                    "Output file '{}' needs an image extension when reading from stdin",
+                    output_file
+                ));
+            }
+            (_, None) => {
+                return Err(format!(
+                    "Input file '{}' has no valid extension",
+                    input_file
+                ));
+            }
+        }
     };
 
     let mut corrected_path = parent_dir.to_path_buf();
@@ -90,12 +269,51 @@ pub fn ensure_valid_output_file(
         .ok_or_else(|| "Failed to convert path to string".to_string())
 }
 
+fn is_svg(extension: &str) -> bool {
+    extension.eq_ignore_ascii_case("svg")
+}
+
+/// Parses an output size such as `4000x3000` into `(width, height)`.
+pub fn parse_output_size(size: &str) -> Result<(u32, u32), String> {
+    let (width, height) = size.split_once(['x', 'X']).ok_or("Expected WIDTHxHEIGHT")?;
+    let parse = |side: &str| match side.trim().parse::<u32>() {
+        Ok(0) | Err(_) => Err(format!("Invalid dimension '{}'", side)),
+        Ok(side) => Ok(side),
+    };
+    Ok((parse(width)?, parse(height)?))
+}
+
+/// Which iterations get captured as animation frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureSchedule {
+    /// A frame every N iterations.
+    Every(u32),
+    /// Frames at iterations 1, 2, 4, 8, ... so early splits get more frames.
+    Doubling,
+}
+
+impl CaptureSchedule {
+    pub fn captures(&self, iteration: u32) -> bool {
+        match self {
+            CaptureSchedule::Every(delta) => iteration.is_multiple_of(*delta),
+            CaptureSchedule::Doubling => iteration.is_power_of_two(),
+        }
+    }
+
+    fn file_suffix(&self) -> String {
+        match self {
+            CaptureSchedule::Every(delta) => format!("delta{}", delta),
+            CaptureSchedule::Doubling => "exp".to_string(),
+        }
+    }
+}
+
 pub fn default_output_file(
     input_file: &str,
-    gif: bool,
     iterations: u32,
     has_outline: bool,
-    gif_delta: Option<u32>,
+    capture: Option<CaptureSchedule>,
+    format: Option<StillFormat>,
 ) -> Result<String, String> {
     let input_path = Path::new(input_file);
 
@@ -112,21 +330,23 @@ pub fn default_output_file(
     let parent_dir = input_path.parent().unwrap_or_else(|| Path::new(""));
     let mut out_path = parent_dir.to_path_buf();
 
-    let filename = match (gif, gif_delta, has_outline) {
-        (true, Some(delta), true) => {
-            format!("{}-compressed-{}-delta{}-outline", stem, iterations, delta)
-        }
-        (true, Some(delta), false) => format!("{}-compressed-{}-delta{}", stem, iterations, delta),
-        (true, None, true) => format!("{}-compressed-{}-outline", stem, iterations),
-        (true, None, false) => format!("{}-compressed-{}", stem, iterations),
-        (false, _, true) => format!("{}-compressed-{}-outline", stem, iterations),
-        (false, _, false) => format!("{}-compressed-{}", stem, iterations),
-    };
+    let mut filename = format!("{}-compressed-{}", stem, iterations);
+    if let Some(capture) = capture {
+        filename.push('-');
+        filename.push_str(&capture.file_suffix());
+    }
+    if has_outline {
+        filename.push_str("-outline");
+    }
 
     out_path.push(filename);
 
-    if gif {
+    // Animations default to GIF, still images to the requested format or
+    // that of the input
+    if capture.is_some() {
         out_path.set_extension("gif");
+    } else if let Some(format) = format {
+        out_path.set_extension(format.extension());
     } else {
         out_path.set_extension(extension.to_lowercase());
     }
@@ -137,12 +357,47 @@ pub fn default_output_file(
         .ok_or_else(|| "Failed to convert path to string".to_string())
 }
 
-pub fn load_image_data(input_file: &str) -> Result<ImageData, Box<dyn std::error::Error>> {
+/// Appends the iteration count to the file stem, e.g. `out.png` -> `out-100.png`,
+/// so one output path can be shared by several iteration levels.
+pub fn level_output_file(output_file: &str, iterations: u32) -> Result<String, String> {
+    let output_path = Path::new(output_file);
+
+    let stem = output_path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .ok_or_else(|| format!("Invalid output file path: '{}'", output_file))?;
+
+    let mut level_path = output_path.with_file_name(format!("{}-{}", stem, iterations));
+    if let Some(extension) = output_path.extension() {
+        level_path.set_extension(extension);
+    }
+
+    level_path
+        .to_str()
+        .map(|s| s.to_string())
+        .ok_or_else(|| "Failed to convert path to string".to_string())
+}
+
+/// Loads the input image, from stdin if `input_file` is `-`, and its prefix
+/// sums. The decoded image and the size of the encoded one are kept for
+/// measuring the result, and the metadata for the outputs.
+pub fn load_image_data(
+    input_file: &str,
+    edge_map: bool,
+) -> Result<(ImageData, RgbImage, u64, Metadata), Box<dyn std::error::Error>> {
     print_step("Loading image data");
-    match ImageData::from_path(input_file) {
-        Ok(data) => {
+    let loaded = read_input(input_file).and_then(|(image, bytes, metadata)| {
+        Ok((
+            ImageData::from_image(&image, edge_map)?,
+            image,
+            bytes,
+            metadata,
+        ))
+    });
+    match loaded {
+        Ok(loaded) => {
             print_success();
-            Ok(data)
+            Ok(loaded)
         }
         Err(e) => {
             print_failure();
@@ -151,91 +406,431 @@ pub fn load_image_data(input_file: &str) -> Result<ImageData, Box<dyn std::error
     }
 }
 
-pub fn process_gif_compression(
+/// Decodes the input the right way up, guessing the format of stdin from
+/// its first bytes.
+fn read_input(input_file: &str) -> Result<(RgbImage, u64, Metadata), String> {
+    if input_file != STDIO {
+        let bytes = std::fs::metadata(input_file).map_or(0, |metadata| metadata.len());
+        return metadata::open(input_file)
+            .map(|(image, metadata)| (image.to_rgb8(), bytes, metadata))
+            .map_err(|_| format!("Failed to open image file: {}", input_file));
+    }
+
+    let mut encoded = Vec::new();
+    io::stdin()
+        .read_to_end(&mut encoded)
+        .map_err(|e| format!("Failed to read stdin: {}", e))?;
+    let (image, metadata) = metadata::load_from_memory(&encoded)
+        .map_err(|e| format!("Failed to decode image from stdin: {}", e))?;
+    Ok((image.to_rgb8(), encoded.len() as u64, metadata))
+}
+
+pub fn load_checkpoint(
+    checkpoint_file: &str,
+    image_data: ImageData,
+    options: SplitOptions,
+) -> Result<QuadTree, Box<dyn std::error::Error>> {
+    print_step("Restoring checkpoint");
+    let restored = File::open(checkpoint_file)
+        .map_err(|e| format!("Unable to open checkpoint '{}': {}", checkpoint_file, e))
+        .and_then(|file| QuadTree::restore(image_data, options, &mut BufReader::new(file)));
+    match restored {
+        Ok(quad_tree) => {
+            print_success();
+            Ok(quad_tree)
+        }
+        Err(e) => {
+            print_failure();
+            Err(e.into())
+        }
+    }
+}
+
+pub fn save_checkpoint(
+    quad_tree: &QuadTree,
+    checkpoint_file: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    print_step("Saving checkpoint");
+    let result = AtomicFile::create(checkpoint_file, true).and_then(|(atomic, file)| {
+        let mut writer = BufWriter::new(file);
+        quad_tree.snapshot(&mut writer)?;
+        drop(writer.into_inner()?);
+        atomic.commit()
+    });
+    match result {
+        Ok(()) => {
+            print_success();
+            Ok(())
+        }
+        Err(e) => {
+            print_failure();
+            Err(format!("Error saving checkpoint: {}", e).into())
+        }
+    }
+}
+
+/// Runs every split first, then replays the captured states. Knowing the
+/// final tree up front lets GIF output share one palette across all frames.
+#[allow(clippy::too_many_arguments)]
+pub fn process_animated_compression(
     quad_tree: &mut QuadTree,
     iterations: u32,
-    delta: u32,
-    outline_rgb: Option<RGB<u8>>,
+    schedule: CaptureSchedule,
+    timing: FrameTiming,
+    format: AnimationFormat,
+    options: &RenderOptions,
+    quality: &QualityCheck,
     output_file: &str,
+    force: bool,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    println!(
-        "Generating animated GIF with {} total iterations...",
+    status!(
+        "Generating animated {} with {} total iterations...",
+        format.name(),
         iterations
     );
-    let mut frames = Vec::new();
+    let start = quad_tree.splits();
+    split_until(quad_tree, iterations, |_| Ok(()))?;
+
+    // Always end on the final result
+    let end = quad_tree.splits();
+    let mut captures: Vec<u32> = (start + 1..=end)
+        .filter(|&i| schedule.captures(i))
+        .collect();
+    if captures.last().is_none_or(|&last| last != end) && end != start {
+        captures.push(end);
+    }
+
+    let palette = (format == AnimationFormat::Gif).then(|| {
+        print_step("Building global palette");
+        let palette = GlobalPalette::for_tree(quad_tree, options);
+        print_success();
+        palette
+    });
 
     // Initial frame
     print_step("Rendering initial frame");
-    let buf = quad_tree.render_rgba(outline_rgb);
-    let width = buf.width() as u16;
-    let height = buf.height() as u16;
-    let mut raw_data = buf.into_raw();
-    frames.push(Frame::from_rgba_speed(width, height, &mut raw_data, 10));
+    let first_frame = quad_tree.render_rgba_region(options, quad_tree.full_region(), start);
+    // PNG sequences write each frame file atomically instead
+    let (mut animation, atomic) = if format == AnimationFormat::PngSequence {
+        (
+            AnimationWriter::png_sequence(output_file, first_frame, timing, force)?,
+            None,
+        )
+    } else {
+        let (atomic, file) =
+            AtomicFile::create(output_file, force).map_err(|_| "Unable to create output file")?;
+        (
+            AnimationWriter::new(format, BufWriter::new(file), first_frame, timing, palette)?,
+            Some(atomic),
+        )
+    };
     print_success();
 
-    // Process iterations
-    for i in 1..=iterations {
-        print_progress(i as usize, iterations as usize, "Processing");
+    // Stream each captured state to the encoder, covering only what changed
+    let mut previous = start;
+    for (i, &capture) in captures.iter().enumerate() {
+        print_progress(i + 1, captures.len(), "Rendering frames");
+
+        // Without a changed region nothing moved, so an empty frame keeps the timing
+        let region = quad_tree
+            .changed_region(previous, capture)
+            .unwrap_or(((0, 0), (0, 0)));
+        let pixels = quad_tree.render_rgba_region(options, region, capture);
+        animation.push_region(quad_tree.output_region(options, region), pixels)?;
+        Event::new("frame_written")
+            .integer("frame", i as u64 + 1)
+            .integer("iteration", capture as u64)
+            .emit();
+        previous = capture;
+    }
 
-        if let Err(err) = quad_tree.split_next() {
-            return Err(format!("Error during quad tree split: {}", err).into());
+    print_step(&format!("Finishing {}", format.name()));
+    let finished = animation.finish().and_then(|frames| {
+        atomic
+            .map_or(Ok(()), AtomicFile::commit)
+            .map(|_| frames)
+            .map_err(|e| format!("Unable to save output file: {}", e))
+    });
+    match finished {
+        Ok(frames) => {
+            print_success();
+            status!("Wrote {} frames", frames);
+            Event::new("output_written")
+                .string("output_file", output_file)
+                .integer("iterations", quad_tree.splits() as u64)
+                .integer("frames", frames as u64)
+                .emit();
+            report_quality(quad_tree, quality, output_file, force)
+        }
+        Err(e) => {
+            print_failure();
+            Err(e.into())
         }
+    }
+}
+
+/// Runs the split loop once up to the highest level in `outputs`, saving an
+/// image each time the tree reaches one of the iteration counts. `outputs`
+/// must be sorted by iteration count.
+pub fn process_static_compression(
+    quad_tree: &mut QuadTree,
+    outputs: &[(u32, String)],
+    options: &RenderOptions,
+    encoding: &EncodeOptions,
+    quality: &QualityCheck,
+    force: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(&(iterations, _)) = outputs.last() else {
+        return Ok(());
+    };
+
+    status!("Processing {} iterations...", iterations);
+    let mut pending = outputs.iter().peekable();
+    while let Some((_, output_file)) = pending.next_if(|(level, _)| *level <= quad_tree.splits()) {
+        save_image(quad_tree, options, output_file, encoding, force)?;
+        report_quality(quad_tree, quality, output_file, force)?;
+    }
 
-        if i % delta == 0 {
-            let buf = quad_tree.render_rgba(outline_rgb);
-            let mut raw_data = buf.into_raw();
-            frames.push(Frame::from_rgba_speed(width, height, &mut raw_data, 10));
+    split_until(quad_tree, iterations, |quad_tree| {
+        let i = quad_tree.splits();
+        while let Some((_, output_file)) = pending.next_if(|(level, _)| *level == i) {
+            if i != iterations && output::interactive() {
+                status!();
+            }
+            save_image(quad_tree, options, output_file, encoding, force)?;
+            report_quality(quad_tree, quality, output_file, force)?;
         }
+        Ok(())
+    })?;
+
+    // Levels beyond convergence all get the final image
+    for (_, output_file) in pending {
+        save_image(quad_tree, options, output_file, encoding, force)?;
+        report_quality(quad_tree, quality, output_file, force)?;
     }
 
-    encode_gif(frames, output_file)?;
     Ok(())
 }
 
-pub fn encode_gif(frames: Vec<Frame>, output_file: &str) -> Result<(), Box<dyn std::error::Error>> {
-    print_step("Encoding GIF");
-    let file = File::create(output_file).map_err(|_| "Unable to create output file")?;
-    let writer = BufWriter::new(file);
-    let mut encoder = GifEncoder::new(writer, frames[0].width, frames[0].height, &[])?;
-    encoder.set_repeat(Repeat::Infinite)?;
+/// Iterations after `start` and up to `end` at which the rate-distortion
+/// curve is sampled, about ten per decade, always including `end`.
+fn rd_checkpoints(start: u32, end: u32) -> Vec<u32> {
+    let mut checkpoints: Vec<u32> = (0..)
+        .map(|step| 10f64.powf(step as f64 / 10.0).round() as u32)
+        .take_while(|&iteration| iteration < end)
+        .filter(|&iteration| iteration > start)
+        .collect();
+    checkpoints.dedup();
+    checkpoints.push(end);
+    checkpoints
+}
 
-    for (i, frame) in frames.into_iter().enumerate() {
-        encoder
-            .write_frame(&frame)
-            .map_err(|_| format!("Error encoding gif frame {}", i))?;
+/// Runs the split loop up to `iterations`, writing the size and error of
+/// the tree at logarithmic checkpoints to a CSV file. The error is tracked
+/// by the tree as it splits, so nothing is rendered.
+pub fn process_rd_curve(
+    quad_tree: &mut QuadTree,
+    iterations: u32,
+    csv_file: &str,
+    force: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (atomic, file) =
+        AtomicFile::create(csv_file, force).map_err(|_| "Unable to create rate-distortion file")?;
+    let mut writer = BufWriter::new(file);
+
+    writeln!(writer, "iterations,leaves,estimated_bytes,mse,psnr")?;
+    let mut write_row = |quad_tree: &QuadTree| -> io::Result<()> {
+        let leaves = quad_tree.leaf_count();
+        writeln!(
+            writer,
+            "{},{},{},{:.4},{:.4}",
+            quad_tree.splits(),
+            leaves,
+            tree_bits(quad_tree.node_count(), leaves).div_ceil(8),
+            quad_tree.mse(),
+            quad_tree.psnr()
+        )
+    };
+
+    status!("Recording rate-distortion curve to {}...", csv_file);
+    write_row(quad_tree)?;
+
+    let mut checkpoints = rd_checkpoints(quad_tree.splits(), iterations)
+        .into_iter()
+        .peekable();
+    let outcome = split_until(quad_tree, iterations, |quad_tree| {
+        if checkpoints.next_if_eq(&quad_tree.splits()).is_some() {
+            write_row(quad_tree)?;
+        }
+        Ok(())
+    })?;
+    if outcome != SplitOutcome::Split {
+        write_row(quad_tree)?;
     }
-    print_success();
+
+    drop(writer.into_inner()?);
+    atomic.commit()?;
     Ok(())
 }
 
-pub fn process_static_compression(
-    quad_tree: &mut QuadTree,
-    iterations: u32,
-    outline_rgb: Option<RGB<u8>>,
+/// What the quality of each output is measured against.
+pub struct QualityCheck {
+    pub original: RgbImage,
+    /// Size of the input file.
+    pub original_bytes: u64,
+    /// Also write the metrics to a JSON file next to each output.
+    pub json_report: bool,
+}
+
+/// Prints the quality metrics of the tree's current state, and writes them
+/// to the output's report file if requested.
+pub fn report_quality(
+    quad_tree: &QuadTree,
+    quality: &QualityCheck,
     output_file: &str,
+    force: bool,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    println!("Processing {} iterations...", iterations);
-    for i in 1..=iterations {
-        print_progress(i as usize, iterations as usize, "Processing");
+    let metrics = Metrics::measure(quad_tree, &quality.original, quality.original_bytes);
+    metrics.print();
+    metrics.event(output_file).emit();
+
+    if quality.json_report {
+        let report = report_file(output_file);
+        print_step(&format!("Writing report {}", report));
+        match atomic_file::write(&report, metrics.to_json(output_file), force) {
+            Ok(_) => print_success(),
+            Err(err) => {
+                print_failure();
+                return Err(format!("Error writing report: {}", err).into());
+            }
+        }
+    }
 
-        if let Err(err) = quad_tree.split_next() {
-            return Err(format!("Error during quad tree split: {}", err).into());
+    Ok(())
+}
+
+/// Encodes `image` as `format`. Encoders may seek, which a pipe can't, so
+/// this goes through memory, which also lets the EXIF block be added
+/// afterwards.
+fn encode_raster(
+    image: &RgbImage,
+    format: ImageFormat,
+    encoding: &EncodeOptions,
+) -> ImageResult<Vec<u8>> {
+    let mut encoded = Cursor::new(Vec::new());
+    match format {
+        ImageFormat::Jpeg => {
+            let mut encoder = match encoding.jpeg_quality {
+                Some(quality) => JpegEncoder::new_with_quality(&mut encoded, quality),
+                None => JpegEncoder::new(&mut encoded),
+            };
+            if let Some(icc_profile) = &encoding.metadata.icc_profile {
+                encoder
+                    .set_icc_profile(icc_profile.clone())
+                    .map_err(ImageError::Unsupported)?;
+            }
+            image.write_with_encoder(encoder)?;
+        }
+        ImageFormat::Png => {
+            let mut encoder = PngEncoder::new_with_quality(
+                &mut encoded,
+                encoding.png_compression,
+                FilterType::default(),
+            );
+            if let Some(icc_profile) = &encoding.metadata.icc_profile {
+                encoder
+                    .set_icc_profile(icc_profile.clone())
+                    .map_err(ImageError::Unsupported)?;
+            }
+            image.write_with_encoder(encoder)?;
         }
+        format => image.write_to(&mut encoded, format)?,
     }
+    Ok(match &encoding.metadata.exif {
+        Some(exif) => metadata::with_exif(encoded.into_inner(), format, exif),
+        None => encoded.into_inner(),
+    })
+}
+
+/// The render saved as a raster image: heatmaps get a legend below.
+fn render_raster(quad_tree: &QuadTree, options: &RenderOptions) -> RgbImage {
+    if options.color_mode != ColorMode::Average {
+        with_legend(&quad_tree.render_rgb(options))
+    } else {
+        quad_tree.render_rgb(options)
+    }
+}
 
-    save_image(quad_tree, outline_rgb, output_file)?;
+/// Writes the image in `format` to `writer`.
+fn write_image(
+    quad_tree: &QuadTree,
+    options: &RenderOptions,
+    format: StillFormat,
+    encoding: &EncodeOptions,
+    writer: &mut impl Write,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match format {
+        StillFormat::Svg => write_svg(quad_tree, options, writer)?,
+        StillFormat::Raster(format) => {
+            let encoded = encode_raster(&render_raster(quad_tree, options), format, encoding)?;
+            writer.write_all(&encoded)?;
+        }
+    }
+    writer.flush()?;
     Ok(())
 }
 
+/// Saves the current state of the tree to `output_file`, or to stdout if
+/// it is `-`.
 pub fn save_image(
     quad_tree: &QuadTree,
-    outline_rgb: Option<RGB<u8>>,
+    options: &RenderOptions,
     output_file: &str,
+    encoding: &EncodeOptions,
+    force: bool,
 ) -> Result<(), Box<dyn std::error::Error>> {
     print_step("Saving image");
-    match quad_tree.render_rgb(outline_rgb).save(output_file) {
+    let format = match encoding.format {
+        Some(format) => Ok(format),
+        None if output_file == STDIO => Err("writing to stdout requires a format".to_string()),
+        None => StillFormat::from_path(output_file),
+    };
+    let result = format.map_err(Into::into).and_then(|format| {
+        if output_file == STDIO {
+            write_image(
+                quad_tree,
+                options,
+                format,
+                encoding,
+                &mut io::stdout().lock(),
+            )
+        } else {
+            let (atomic, file) = AtomicFile::create(output_file, force)?;
+            let mut writer = BufWriter::new(file);
+            write_image(quad_tree, options, format, encoding, &mut writer)?;
+            let file = writer.into_inner()?;
+            if let Some(modified) = encoding.metadata.modified {
+                file.set_modified(modified)?;
+            }
+            drop(file);
+            atomic.commit().map_err(Into::into)
+        }
+    });
+    match result {
         Ok(_) => {
             print_success();
+            Event::new("output_written")
+                .string("output_file", output_file)
+                .integer("iterations", quad_tree.splits() as u64)
+                .emit();
+            if options.color_mode != ColorMode::Average {
+                let range = quad_tree.color_range(options.color_mode);
+                status!(
+                    "Legend: {} from 0 (left) to {} (right)",
+                    options.color_mode.name(),
+                    (range * 10.0).round() / 10.0
+                );
+            }
             Ok(())
         }
         Err(err) => {
@@ -244,3 +839,70 @@ pub fn save_image(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn missing_outputs_and_stdout_are_never_refused() {
+        let missing =
+            std::env::temp_dir().join(format!("img-compressor-missing-{}.png", std::process::id()));
+        let missing = missing.to_str().unwrap();
+        assert!(check_overwrite(missing, "in.png", false).is_ok());
+        assert!(check_overwrite(STDIO, STDIO, false).is_ok());
+    }
+
+    #[test]
+    fn existing_outputs_need_force() {
+        let directory =
+            std::env::temp_dir().join(format!("img-compressor-overwrite-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let input = directory.join("a.png");
+        let output = directory.join("b.png");
+        fs::write(&input, b"").unwrap();
+        fs::write(&output, b"").unwrap();
+        let input = input.to_str().unwrap();
+        let output = output.to_str().unwrap();
+
+        let refused = check_overwrite(output, input, false);
+        let forced = check_overwrite(output, input, true);
+        fs::remove_dir_all(&directory).unwrap();
+        assert!(refused.unwrap_err().contains("--force"));
+        assert!(forced.is_ok());
+    }
+
+    #[test]
+    fn the_input_is_refused_even_with_force() {
+        let directory =
+            std::env::temp_dir().join(format!("img-compressor-same-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.join("a.png"), b"").unwrap();
+        let input = format!("{}/a.png", directory.display());
+        let output = format!("{}/./a.png", directory.display());
+
+        let same = check_overwrite(&input, &input, true);
+        let respelled = check_overwrite(&output, &input, true);
+        fs::remove_dir_all(&directory).unwrap();
+        assert!(same.unwrap_err().contains("is the input file"));
+        assert!(respelled.unwrap_err().contains("is the input file"));
+    }
+
+    #[test]
+    fn explicit_output_extensions_are_kept_when_supported() {
+        let corrected =
+            |output: &str, input: &str| ensure_valid_output_file(output, input, false).unwrap();
+        assert_eq!(corrected("result.jpg", "in.png"), "result.jpg");
+        assert_eq!(corrected("result.svg", "in.png"), "result.svg");
+        assert_eq!(corrected("out/result.webp", STDIO), "out/result.webp");
+        assert_eq!(corrected("result", "in.PNG"), "result.png");
+        assert_eq!(corrected("result.xyz", "in.png"), "result.png");
+        assert!(ensure_valid_output_file("result", STDIO, false).is_err());
+
+        assert_eq!(
+            ensure_valid_output_file("result.jpg", "in.png", true).unwrap(),
+            "result.gif"
+        );
+    }
+}